@@ -8,7 +8,9 @@ use std::{
 use clap::{Parser, ValueEnum};
 use color_eyre::eyre::{self, WrapErr};
 use llm::{
-    ggml_format, ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias,
+    ggml_format,
+    samplers::{Grammar, GrammarConstraint},
+    ContextShift, ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias,
     LoadProgress, Model, ModelKVMemoryType, ModelParameters, TokenBias, TokenizerSource,
 };
 use rand::SeedableRng;
@@ -64,6 +66,9 @@ pub struct Infer {
     #[command(flatten)]
     pub prompt: Prompt,
 
+    #[command(flatten)]
+    pub grammar_file: GrammarFile,
+
     /// Hide the prompt in the generation.
     ///
     /// By default, the prompt tokens will be shown as they are fed to the model.
@@ -165,6 +170,9 @@ pub struct Repl {
 
     #[command(flatten)]
     pub generate: Generate,
+
+    #[command(flatten)]
+    pub grammar_file: GrammarFile,
 }
 
 #[derive(Parser, Debug)]
@@ -243,8 +251,8 @@ pub struct Generate {
     #[arg(long, default_value_t = 8)]
     pub batch_size: usize,
 
-    /// Size of the 'last N' buffer that is used for the `repeat_penalty`
-    /// option. In tokens.
+    /// Size of the 'last N' buffer that is used for the `repeat_penalty`,
+    /// `frequency_penalty` and `presence_penalty` options. In tokens.
     #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
 
@@ -254,6 +262,21 @@ pub struct Generate {
     #[arg(long, default_value_t = 1.30)]
     pub repeat_penalty: f32,
 
+    /// The penalty for each time a token has been repeated, which is
+    /// subtracted from its logit. 0.0 disables it.
+    #[arg(long, default_value_t = 0.0)]
+    pub frequency_penalty: f32,
+
+    /// The penalty for a token that has been used at all, which is subtracted
+    /// from its logit. 0.0 disables it.
+    #[arg(long, default_value_t = 0.0)]
+    pub presence_penalty: f32,
+
+    /// Forbids repeating any sequence of this many tokens that has already
+    /// occurred. 0 disables it.
+    #[arg(long, default_value_t = 0)]
+    pub no_repeat_ngram_size: usize,
+
     /// Temperature
     #[arg(long, default_value_t = 0.80)]
     pub temperature: f32,
@@ -267,6 +290,23 @@ pub struct Generate {
     #[arg(long, default_value_t = 0.95)]
     pub top_p: f32,
 
+    /// Min-p: Only words whose probability is at least this fraction of the
+    /// probability of the most likely word are kept for sampling. 0.0 disables it.
+    #[arg(long, default_value_t = 0.0)]
+    pub min_p: f32,
+
+    /// Tail-free sampling: Removes the tail of unlikely words, found using the
+    /// second derivative of their probabilities. Lower values remove more
+    /// words; 1.0 disables it.
+    #[arg(long, default_value_t = 1.0)]
+    pub tfs_z: f32,
+
+    /// Locally typical sampling: The cumulative probability after which no more
+    /// words are kept, taking the words whose surprise is closest to the
+    /// expected surprise first. 1.0 disables it.
+    #[arg(long, default_value_t = 1.0)]
+    pub typical_p: f32,
+
     /// Specifies the seed to use during sampling. Note that, depending on
     /// hardware, the same seed may lead to different results on two separate
     /// machines.
@@ -299,6 +339,12 @@ pub struct Generate {
     #[arg(long, default_value_t = false)]
     pub ignore_eos: bool,
 
+    /// When the context window fills up, keep this many tokens from the start of the
+    /// session, discard half of the rest, and continue generating. If not set, inference
+    /// stops when the context window is full.
+    #[arg(long, default_value = None)]
+    pub context_shift_keep: Option<usize>,
+
     /// Whether to use GPU acceleration when available
     #[arg(long, default_value_t = false)]
     pub use_gpu: bool,
@@ -336,6 +382,7 @@ impl Generate {
             memory_v_type: mem_typ,
             n_batch: self.batch_size,
             n_threads: self.num_threads(),
+            context_shift: self.context_shift_keep.map(|keep| ContextShift { keep }),
         }
     }
 
@@ -347,22 +394,55 @@ impl Generate {
         }
     }
 
-    pub fn inference_parameters(&self, eot: llm::TokenId) -> InferenceParameters {
-        InferenceParameters {
-            sampler: Arc::new(llm::samplers::TopPTopK {
-                top_k: self.top_k,
-                top_p: self.top_p,
-                repeat_penalty: self.repeat_penalty,
+    pub fn inference_parameters(
+        &self,
+        eot: llm::TokenId,
+        grammar: Option<GrammarConstraint>,
+    ) -> InferenceParameters {
+        use llm::samplers::{
+            Bias, FrequencyPresencePenalty, LocallyTypical, MinP, NoRepeatNgram, RepetitionPenalty,
+            SampleRandom, SamplerChain, TailFree, Temperature, TopK, TopP,
+        };
+
+        let bias_tokens = self.token_bias.clone().unwrap_or_else(|| {
+            if self.ignore_eos {
+                TokenBias::new(vec![(eot, -1.0)])
+            } else {
+                TokenBias::default()
+            }
+        });
+
+        // Stages that are disabled by their default values have no effect, so this
+        // behaves like `TopPTopK` unless one of the other strategies is requested.
+        let mut sampler = SamplerChain::new(SampleRandom)
+            .with(RepetitionPenalty {
+                penalty: self.repeat_penalty,
+                last_n: self.repeat_last_n,
+            })
+            .with(FrequencyPresencePenalty {
+                frequency_penalty: self.frequency_penalty,
+                presence_penalty: self.presence_penalty,
+                last_n: self.repeat_last_n,
+            })
+            .with(NoRepeatNgram {
+                size: self.no_repeat_ngram_size,
+            })
+            .with(Temperature {
                 temperature: self.temperature,
-                bias_tokens: self.token_bias.clone().unwrap_or_else(|| {
-                    if self.ignore_eos {
-                        TokenBias::new(vec![(eot, -1.0)])
-                    } else {
-                        TokenBias::default()
-                    }
-                }),
-                repetition_penalty_last_n: self.repeat_last_n,
-            }),
+            })
+            .with(Bias { bias_tokens })
+            .with(TopK { k: self.top_k })
+            .with(TailFree { z: self.tfs_z })
+            .with(LocallyTypical { p: self.typical_p })
+            .with(TopP { p: self.top_p })
+            .with(MinP { p: self.min_p });
+        if let Some(grammar) = grammar {
+            // The grammar goes first, so that the other stages only see valid tokens.
+            sampler.stages.insert(0, Box::new(grammar));
+        }
+
+        InferenceParameters {
+            sampler: Arc::new(sampler),
         }
     }
 }
@@ -442,10 +522,10 @@ pub struct ModelLoad {
     /// want to use a larger context size, you will need to retrain the model,
     /// or use a model that was trained with a larger context size.
     ///
-    /// Alternate methods to extend the context, including
-    /// [context clearing](https://github.com/rustformers/llm/issues/77) are
-    /// being investigated, but are not yet implemented. Additionally, these
-    /// will likely not perform as well as a model with a larger context size.
+    /// To keep generating once the context is full, use `--context-shift-keep`,
+    /// which discards older tokens to make room. The discarded tokens are
+    /// forgotten, so this will not perform as well as a model with a larger
+    /// context size.
     #[arg(long, default_value_t = 2048)]
     pub num_ctx_tokens: usize,
 
@@ -574,6 +654,31 @@ impl PromptFile {
     }
 }
 
+#[derive(Parser, Debug)]
+pub struct GrammarFile {
+    /// A file containing a GBNF grammar that the generated text must match.
+    /// Generation starts from its `root` rule.
+    #[arg(long, default_value = None)]
+    pub grammar_file: Option<PathBuf>,
+}
+impl GrammarFile {
+    pub fn constraint(&self, model: &dyn Model) -> eyre::Result<Option<GrammarConstraint>> {
+        let Some(path) = &self.grammar_file else {
+            return Ok(None);
+        };
+
+        let source = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read grammar file at {path:?}"))?;
+        let grammar = Grammar::parse(&source)
+            .wrap_err_with(|| format!("Could not parse grammar file at {path:?}"))?;
+        Ok(Some(GrammarConstraint::new(
+            grammar,
+            model.tokenizer(),
+            model.eot_token_id(),
+        )))
+    }
+}
+
 pub fn read_prompt_file(path: &Path) -> eyre::Result<String> {
     std::fs::read_to_string(path)
         .wrap_err_with(|| format!("Could not read prompt file at {path:?}"))
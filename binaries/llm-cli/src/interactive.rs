@@ -18,10 +18,11 @@ pub fn repl(
         generate,
         model_load,
         prompt_file,
+        grammar_file,
     }: &Repl,
 ) -> eyre::Result<()> {
     let (inference_session_config, parameters, model, mut rng) =
-        initialize_common_state(generate, model_load)?;
+        initialize_common_state(generate, model_load, Some(grammar_file))?;
 
     let template = prompt_file.contents()?;
 
@@ -34,16 +35,20 @@ pub fn repl(
             .as_deref()
             .map(|template| util::process_prompt(template, &line))
             .unwrap_or(line);
-        feed_prompt_with_spinner(model, &mut session, prompt)?;
+        if model.supports_rewind() {
+            // Only evaluate the part of the prompt that differs from the last one.
+            refeed_prompt_with_spinner(model, &mut session, prompt)?;
+        } else {
+            session = create_session(model, inference_session_config);
+            feed_prompt_with_spinner(model, &mut session, prompt)?;
+        }
 
         session.infer::<Infallible>(
             model,
             &mut rng,
             &llm::InferenceRequest {
-                prompt: "".into(),
-                parameters: &parameters,
-                play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                ..llm::InferenceRequest::new("".into(), &parameters)
             },
             &mut Default::default(),
             |r| {
@@ -57,7 +62,6 @@ pub fn repl(
         if !session_ends_with_newline(&session) {
             println!();
         }
-        session = create_session(model, inference_session_config);
 
         Ok(())
     })
@@ -72,10 +76,12 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
     } = args;
 
     let (inference_session_config, parameters, model, mut rng) =
-        initialize_common_state(generate, model_load)?;
+        initialize_common_state(generate, model_load, None)?;
 
     let prelude_prompt = std::fs::read_to_string(prelude_prompt_file)?;
     let message_prompt_prefix = args.message_prompt_prefix()?;
+    // The model has finished its response once it starts writing the next message.
+    let stop_sequences = [message_prompt_prefix.clone()];
 
     let model = model.as_ref();
     let mut session = create_session(model, inference_session_config);
@@ -96,13 +102,17 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
             model,
             &mut rng,
             &llm::InferenceRequest {
-                prompt: (&prompt).into(),
-                parameters: &parameters,
-                play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                stop_sequences: &stop_sequences,
+                ..llm::InferenceRequest::new((&prompt).into(), &parameters)
             },
             &mut Default::default(),
-            llm::conversation_inference_callback(&message_prompt_prefix, util::print_token),
+            |r| {
+                if let llm::InferenceResponse::InferredToken(t) = r {
+                    util::print_token(t);
+                }
+                Ok(llm::InferenceFeedback::Continue)
+            },
         )?;
 
         if !session_ends_with_newline(&session) {
@@ -116,6 +126,7 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
 fn initialize_common_state(
     generate: &crate::cli_args::Generate,
     model_load: &crate::cli_args::ModelLoad,
+    grammar_file: Option<&crate::cli_args::GrammarFile>,
 ) -> eyre::Result<(
     llm::InferenceSessionConfig,
     llm::InferenceParameters,
@@ -123,9 +134,13 @@ fn initialize_common_state(
     rand::rngs::StdRng,
 )> {
     let model = model_load.load(generate.use_gpu)?;
+    let grammar = match grammar_file {
+        Some(grammar_file) => grammar_file.constraint(model.as_ref())?,
+        None => None,
+    };
     Ok((
         generate.inference_session_config(),
-        generate.inference_parameters(model.eot_token_id()),
+        generate.inference_parameters(model.eot_token_id(), grammar),
         model,
         generate.rng(),
     ))
@@ -154,6 +169,24 @@ fn feed_prompt_with_spinner(
     Ok(result?)
 }
 
+fn refeed_prompt_with_spinner(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    prompt: String,
+) -> eyre::Result<()> {
+    let sp = spinoff::Spinner::new(spinoff::spinners::Dots2, "".to_string(), None);
+    let result = session.feed_prompt_incremental(
+        model,
+        &prompt,
+        // OutputRequest
+        &mut Default::default(),
+        |_| Ok::<_, Infallible>(llm::InferenceFeedback::Continue),
+    );
+    sp.clear();
+
+    Ok(result?)
+}
+
 fn create_session(
     model: &dyn llm::Model,
     inference_session_config: llm::InferenceSessionConfig,
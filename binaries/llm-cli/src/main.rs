@@ -47,7 +47,10 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
         args.load_session.as_deref(),
         inference_session_config,
     );
-    let parameters = args.generate.inference_parameters(model.eot_token_id());
+    let parameters = args.generate.inference_parameters(
+        model.eot_token_id(),
+        args.grammar_file.constraint(model.as_ref())?,
+    );
 
     let mut rng = args.generate.rng();
 
@@ -59,10 +62,9 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
             model.as_ref(),
             &mut rng,
             &llm::InferenceRequest {
-                prompt: prompt.as_str().into(),
-                parameters: &parameters,
                 play_back_previous_tokens: session_loaded,
                 maximum_token_count: args.generate.num_predict,
+                ..llm::InferenceRequest::new(prompt.as_str().into(), &parameters)
             },
             // OutputRequest
             &mut Default::default(),
@@ -91,10 +93,16 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
             Err(llm::InferenceError::ContextFull) => {
                 log::warn!("Context window full, stopping inference.")
             }
+            Err(llm::InferenceError::ContextShiftFailed(err)) => {
+                log::warn!("Context window full and could not be shifted: {}", err);
+            }
             Err(llm::InferenceError::TokenizationFailed(err)) => {
                 log::error!("A tokenization-related failure occurred: {}", err);
             }
-            Err(llm::InferenceError::UserCallback(_)) | Err(llm::InferenceError::EndOfText) => {
+            Err(llm::InferenceError::UserCallback(_))
+            | Err(llm::InferenceError::EndOfText)
+            | Err(llm::InferenceError::RewindFailed(_))
+            | Err(llm::InferenceError::ForkFailed(_)) => {
                 unreachable!("cannot fail")
             }
         }
@@ -21,7 +21,11 @@ pub fn read_or_create_session(
     load_session: Option<&Path>,
     inference_session_config: InferenceSessionConfig,
 ) -> (InferenceSession, bool) {
-    fn load(model: &dyn Model, path: &Path) -> InferenceSession {
+    fn load(
+        model: &dyn Model,
+        path: &Path,
+        inference_session_config: InferenceSessionConfig,
+    ) -> InferenceSession {
         let file = unwrap_or_exit(File::open(path), || format!("Could not open file {path:?}"));
         let decoder = unwrap_or_exit(Decoder::new(BufReader::new(file)), || {
             format!("Could not create decoder for {path:?}")
@@ -29,24 +33,25 @@ pub fn read_or_create_session(
         let snapshot = unwrap_or_exit(bincode::deserialize_from(decoder), || {
             format!("Could not deserialize inference session from {path:?}")
         });
-        let session = unwrap_or_exit(InferenceSession::from_snapshot(snapshot, model), || {
+        let mut session = unwrap_or_exit(InferenceSession::from_snapshot(snapshot, model), || {
             format!("Could not convert snapshot from {path:?} to session")
         });
+        // This is not stored in the snapshot.
+        session.set_context_shift(inference_session_config.context_shift);
         log::info!("Loaded inference session from {path:?}");
         session
     }
 
     match (persist_session, load_session) {
-        (Some(path), _) if path.exists() => (load(model, path), true),
-        (_, Some(path)) => (load(model, path), true),
+        (Some(path), _) if path.exists() => (load(model, path, inference_session_config), true),
+        (_, Some(path)) => (load(model, path, inference_session_config), true),
         _ => (model.start_session(inference_session_config), false),
     }
 }
 
 /// Write the session
 pub fn write_session(mut session: InferenceSession, path: &Path) {
-    // SAFETY: the session is consumed here, so nothing else can access it.
-    let snapshot = unsafe { session.get_snapshot() };
+    let snapshot = session.snapshot();
     let file = unwrap_or_exit(File::create(path), || {
         format!("Could not create file {path:?}")
     });
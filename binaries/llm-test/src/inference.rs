@@ -64,12 +64,13 @@ fn run_inference(
         model,
         &mut rand::rngs::mock::StepRng::new(0, 1),
         &llm::InferenceRequest {
-            prompt: input.into(),
-            parameters: &llm::InferenceParameters {
-                sampler: Arc::new(DeterministicSampler),
-            },
-            play_back_previous_tokens: false,
             maximum_token_count: Some(maximum_token_count),
+            ..llm::InferenceRequest::new(
+                input.into(),
+                &llm::InferenceParameters {
+                    sampler: Arc::new(DeterministicSampler),
+                },
+            )
         },
         &mut Default::default(),
         |r| match r {
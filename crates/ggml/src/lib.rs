@@ -127,6 +127,15 @@ pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
 /// The maximum length of a `ggml` tensor-name.
 pub const MAX_NAME_LENGTH: usize = sys::GGML_MAX_NAME as usize;
 
+/// The maximum number of nodes in a `ggml` computation graph.
+pub const MAX_NODES: usize = sys::GGML_MAX_NODES as usize;
+
+/// The frequency base of the rotary position embedding applied by [Context::op_rope].
+pub const ROPE_FREQ_BASE: f32 = 10000.0;
+
+/// The frequency scale of the rotary position embedding applied by [Context::op_rope].
+pub const ROPE_FREQ_SCALE: f32 = 1.0;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 /// The type of a value in `ggml`.
 pub enum Type {
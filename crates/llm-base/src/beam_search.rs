@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+
+use partial_sort::PartialSort;
+
+use crate::{
+    util, BatchSequence, InferenceError, InferenceFeedback, InferenceSession, Model, OutputRequest,
+    Prompt, TokenId,
+};
+
+/// Parameters for [InferenceSession::beam_search].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamSearchParameters {
+    /// The number of hypotheses to keep at each step. A width of 1 is greedy decoding.
+    pub beam_width: usize,
+    /// The exponent applied to the length of a hypothesis when scoring it: its score is
+    /// its log-probability divided by `length ^ length_penalty`.
+    ///
+    /// As log-probabilities are negative, values above 0.0 favour longer outputs, and
+    /// 0.0 scores hypotheses by their log-probability alone, which favours shorter outputs.
+    pub length_penalty: f32,
+    /// The maximum number of tokens to generate. The context window of the model
+    /// also limits the number of tokens.
+    pub maximum_token_count: Option<usize>,
+}
+impl Default for BeamSearchParameters {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.0,
+            maximum_token_count: None,
+        }
+    }
+}
+
+/// A sequence of tokens generated by [InferenceSession::beam_search].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// The generated tokens, not including the end-of-text token. These can be
+    /// decoded with [Tokenizer::decode](crate::Tokenizer::decode).
+    pub tokens: Vec<TokenId>,
+    /// The sum of the log-probabilities of the generated tokens, including the
+    /// end-of-text token if it was generated.
+    pub log_probability: f32,
+    /// The score of this hypothesis, after the length penalty was applied.
+    pub score: f32,
+    /// Whether this hypothesis ended with the end-of-text token, rather than by
+    /// reaching the maximum number of tokens.
+    pub finished: bool,
+}
+
+/// A hypothesis that is still being extended.
+struct Beam {
+    tokens: Vec<TokenId>,
+    log_probability: f32,
+    /// A session that has evaluated the prompt and the tokens of this beam, so that only
+    /// the next token has to be evaluated to extend it.
+    session: InferenceSession,
+}
+
+impl InferenceSession {
+    /// Generate text by using beam search over the `prompt`, which keeps the
+    /// [BeamSearchParameters::beam_width] most likely hypotheses at each step.
+    ///
+    /// Unlike [Self::infer], this does not sample, so it is deterministic and finds
+    /// outputs that are more likely as a whole. Each hypothesis is kept in its own copy of
+    /// this session, made with [Self::fork], and the hypotheses are extended together with
+    /// [Model::evaluate_batch]; only the models that evaluate a batch in one graph, such as
+    /// LLaMA, are faster for it. This needs the memory of up to twice the
+    /// [BeamSearchParameters::beam_width] sessions, and the memory of this session must be
+    /// on the CPU. The session must not be empty after the prompt has been fed.
+    ///
+    /// The hypotheses are returned from best to worst, and this session is left
+    /// with the best of them after the prompt.
+    pub fn beam_search<'a, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        parameters: &BeamSearchParameters,
+    ) -> Result<Vec<BeamHypothesis>, InferenceError> {
+        let width = parameters.beam_width.max(1);
+        let eot = model.eot_token_id();
+        let score = |log_probability: f32, len: usize| {
+            log_probability / (len.max(1) as f32).powf(parameters.length_penalty)
+        };
+
+        let prompt = prompt.into();
+        if !prompt.is_empty() {
+            self.feed_prompt(model, prompt, &mut OutputRequest::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })?;
+        }
+        let context = self.tokens.clone();
+
+        // Each step evaluates one more token, which must fit in the context window.
+        let maximum_token_count = parameters
+            .maximum_token_count
+            .unwrap_or(usize::MAX)
+            .min(model.context_size().saturating_sub(context.len() + 1));
+
+        let mut beams = vec![Beam {
+            tokens: vec![],
+            log_probability: 0.0,
+            session: self.fork(model)?,
+        }];
+        let mut finished: Vec<BeamHypothesis> = vec![];
+        for _ in 0..maximum_token_count {
+            // Extend every beam with its most likely tokens, and keep the best of them.
+            let mut candidates = vec![];
+            for (index, beam) in beams.iter().enumerate() {
+                let log_probs = util::log_softmax(&beam.session.last_logits);
+                let mut tokens: Vec<usize> = (0..log_probs.len()).collect();
+                let n = width.min(tokens.len());
+                tokens.partial_sort(n, |&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+                for &token in &tokens[..n] {
+                    candidates.push((
+                        index,
+                        token as TokenId,
+                        beam.log_probability + log_probs[token],
+                    ));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut extended = vec![];
+            for (index, token, log_probability) in candidates {
+                if extended.len() == width {
+                    break;
+                }
+
+                if token == eot {
+                    let tokens = beams[index].tokens.clone();
+                    finished.push(BeamHypothesis {
+                        score: score(log_probability, tokens.len() + 1),
+                        tokens,
+                        log_probability,
+                        finished: true,
+                    });
+                } else {
+                    extended.push((index, token, log_probability));
+                }
+            }
+
+            // The last extension of each beam continues its session, and the others copy it.
+            // The sessions of the beams that are not extended are re-used for the copies.
+            let mut remaining = vec![0; beams.len()];
+            for &(index, _, _) in &extended {
+                remaining[index] += 1;
+            }
+            let mut parents: Vec<_> = beams.drain(..).map(Some).collect();
+            let mut spare_sessions: Vec<_> = parents
+                .iter_mut()
+                .zip(&remaining)
+                .filter(|(_, &remaining)| remaining == 0)
+                .filter_map(|(parent, _)| parent.take())
+                .map(|parent| parent.session)
+                .collect();
+            for (index, token, log_probability) in extended {
+                remaining[index] -= 1;
+                let parent = parents[index].as_ref().unwrap();
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+
+                let mut session = if remaining[index] == 0 {
+                    parents[index].take().unwrap().session
+                } else if let Some(mut session) = spare_sessions.pop() {
+                    session.copy_from(&parent.session)?;
+                    session
+                } else {
+                    parent.session.fork(model)?
+                };
+                session.push_token(model, token);
+                beams.push(Beam {
+                    tokens,
+                    log_probability,
+                    session,
+                });
+            }
+            drop(spare_sessions);
+
+            // Evaluate the new token of every beam at once.
+            let inputs: Vec<_> = beams
+                .iter()
+                .map(|beam| [*beam.tokens.last().unwrap()])
+                .collect();
+            let mut output_requests = vec![OutputRequest::default(); beams.len()];
+            let mut batch: Vec<_> = beams
+                .iter_mut()
+                .zip(&inputs)
+                .zip(&mut output_requests)
+                .map(|((beam, input), output_request)| BatchSequence {
+                    session: &mut beam.session,
+                    input_tokens: input,
+                    output_request,
+                })
+                .collect();
+            if !batch.is_empty() {
+                model.evaluate_batch(&mut batch);
+            }
+
+            // Stop once no active beam is better than the finished hypotheses. As
+            // log-probabilities only decrease, this is a heuristic when longer outputs are
+            // favoured.
+            finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+            finished.truncate(width);
+            let best_active = beams
+                .iter()
+                .map(|b| score(b.log_probability, b.tokens.len()))
+                .max_by(f32::total_cmp);
+            let done = match best_active {
+                None => true,
+                Some(best_active) => {
+                    finished.len() == width
+                        && finished
+                            .last()
+                            .map(|h| h.score)
+                            .unwrap_or(f32::NEG_INFINITY)
+                            >= best_active
+                }
+            };
+            if done {
+                break;
+            }
+        }
+
+        finished.extend(beams.iter().map(|beam| BeamHypothesis {
+            score: score(beam.log_probability, beam.tokens.len()),
+            tokens: beam.tokens.clone(),
+            log_probability: beam.log_probability,
+            finished: false,
+        }));
+        finished.sort_by(|a, b| match b.score.total_cmp(&a.score) {
+            Ordering::Equal => a.tokens.cmp(&b.tokens),
+            ordering => ordering,
+        });
+        finished.truncate(width);
+
+        if let Some(best) = finished.first() {
+            match beams
+                .into_iter()
+                .find(|beam| !best.finished && beam.tokens == best.tokens)
+            {
+                Some(beam) => *self = beam.session,
+                None => {
+                    // The best hypothesis has finished, so no beam holds it, but this
+                    // session is still after the prompt.
+                    let all_tokens: Vec<_> = context.iter().chain(&best.tokens).copied().collect();
+                    self.sync_tokens(model, &all_tokens, &mut OutputRequest::default())?;
+                }
+            }
+        }
+
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_model::{MockModel, EOT};
+
+    #[test]
+    fn test_beam_search_evaluates_new_tokens_only() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+        let parameters = BeamSearchParameters {
+            beam_width: 2,
+            ..Default::default()
+        };
+
+        // The model predicts `y`, `z` and then the end of text after `x`.
+        let prompt: &[TokenId] = &[26];
+        let hypotheses = session.beam_search(&model, prompt, &parameters).unwrap();
+        assert_eq!(hypotheses.len(), 2);
+        assert_eq!(hypotheses[0].tokens, [27, 28]);
+        assert!(hypotheses[0].finished);
+        assert!(hypotheses[0].score > hypotheses[1].score);
+
+        // Every beam is evaluated with a single token at each step.
+        let batch_sizes = model.batch_sizes.lock().unwrap();
+        assert!(batch_sizes.iter().all(|&size| size <= 2));
+
+        assert_eq!(session.tokens(), [26, 27, 28]);
+        assert_eq!(session.n_past, 3);
+        for (position, &token) in session.tokens().iter().enumerate() {
+            assert_eq!(MockModel::stored_token(&session, position), Some(token));
+        }
+        assert_eq!(session.last_logits[EOT as usize], 1.0);
+    }
+
+    #[test]
+    fn test_beam_search_restores_finished_hypothesis() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let mut session = model.start_session(Default::default());
+        session
+            .sync_tokens(&model, &[1, 0], &mut OutputRequest::default())
+            .unwrap();
+
+        // The model predicts the empty `<s>` and then the end of text, so the best
+        // hypothesis finishes, and the beam that held it goes on to other tokens.
+        let parameters = BeamSearchParameters {
+            beam_width: 2,
+            ..Default::default()
+        };
+        let hypotheses = session.beam_search(&model, "", &parameters).unwrap();
+        assert_eq!(hypotheses[0].tokens, [1]);
+        assert!(hypotheses[0].finished);
+
+        assert_eq!(session.tokens(), [1, 0, 1]);
+        assert_eq!(session.n_past, 3);
+        assert_eq!(MockModel::stored_token(&session, 2), Some(1));
+        assert_eq!(session.last_logits[EOT as usize], 1.0);
+    }
+}
@@ -0,0 +1,151 @@
+use std::convert::Infallible;
+
+use crate::{
+    InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
+    Model, OutputRequest, RewindError, TokenId,
+};
+
+/// One of the completions generated by [InferenceSession::infer_best_of].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    /// The generated tokens, including the end-of-text token if it was generated.
+    pub tokens: Vec<TokenId>,
+    /// The generated text, as it would have been passed to the callback of
+    /// [InferenceSession::infer].
+    pub text: String,
+    /// The sum of the log-probabilities of the generated tokens, as reported by
+    /// [InferenceResponse::TokenLogprobs].
+    pub log_probability: f32,
+}
+
+impl InferenceSession {
+    /// Generate `n` independent completions of the prompt of the `request`, and return
+    /// them from the most to the least likely.
+    ///
+    /// The prompt is only fed once. Each completion is then generated like
+    /// [Self::infer], after rewinding to the end of the prompt, so the model must
+    /// [support rewinding](Model::supports_rewind) if `n` is more than one, and the
+    /// session must not be empty after the prompt has been fed. This session is left
+    /// with the most likely completion after the prompt.
+    ///
+    /// The completions are ranked by their [Completion::log_probability], which is
+    /// computed from the predictions of the model before sampling.
+    pub fn infer_best_of(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
+        n: usize,
+    ) -> Result<Vec<Completion>, InferenceError> {
+        if n > 1 && !model.supports_rewind() {
+            return Err(RewindError::UnsupportedArchitecture.into());
+        }
+
+        if !request.prompt.is_empty() {
+            self.feed_prompt(model, request.prompt, output_request, |_| {
+                Ok::<_, Infallible>(InferenceFeedback::Continue)
+            })?;
+        }
+        let prompt_len = self.tokens.len();
+        let prompt_logits = self.last_logits.clone();
+
+        let request = InferenceRequest {
+            prompt: Default::default(),
+            play_back_previous_tokens: false,
+            token_logprobs: Some(request.token_logprobs.unwrap_or(0)),
+            ..*request
+        };
+        let mut completions = vec![];
+        for _ in 0..n {
+            if self.tokens.len() > prompt_len {
+                self.rewind(model, self.tokens.len() - prompt_len)?;
+                self.last_logits.clone_from(&prompt_logits);
+            }
+
+            let mut text = String::new();
+            let mut log_probability = 0.0;
+            self.infer(
+                model,
+                rng,
+                &request,
+                &mut OutputRequest::default(),
+                |response| {
+                    match response {
+                        InferenceResponse::InferredToken(t) => text.push_str(&t),
+                        InferenceResponse::TokenLogprobs(logprobs) => {
+                            log_probability += logprobs.logprob
+                        }
+                        _ => {}
+                    }
+                    Ok::<_, Infallible>(InferenceFeedback::Continue)
+                },
+            )?;
+
+            completions.push(Completion {
+                tokens: self.tokens[prompt_len..].to_vec(),
+                text,
+                log_probability,
+            });
+        }
+        completions.sort_by(|a, b| b.log_probability.total_cmp(&a.log_probability));
+
+        if let Some(best) = completions.first() {
+            let all_tokens: Vec<_> = self.tokens[..prompt_len]
+                .iter()
+                .chain(&best.tokens)
+                .copied()
+                .collect();
+            self.sync_tokens(model, &all_tokens, &mut OutputRequest::default())?;
+        }
+
+        Ok(completions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{mock_model::MockModel, samplers::Sampler, InferenceParameters};
+
+    /// A sampler that picks the given tokens in order.
+    #[derive(Debug)]
+    struct Scripted(Mutex<Vec<TokenId>>);
+    impl Sampler for Scripted {
+        fn sample(&self, _: &[TokenId], _: &[f32], _: &mut dyn rand::RngCore) -> TokenId {
+            self.0.lock().unwrap().remove(0)
+        }
+    }
+
+    #[test]
+    fn test_infer_best_of_returns_to_completion_with_end_of_text() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let mut session = model.start_session(Default::default());
+        // The first completion is the one that the model predicts, and ends with the
+        // empty end of text. The second is less likely, and is generated last.
+        let parameters = InferenceParameters {
+            sampler: Arc::new(Scripted(Mutex::new(vec![28, 2, 5, 6]))),
+        };
+        let request = InferenceRequest {
+            maximum_token_count: Some(2),
+            ..InferenceRequest::new("y".into(), &parameters)
+        };
+
+        let completions = session
+            .infer_best_of(
+                &model,
+                &mut rand::thread_rng(),
+                &request,
+                &mut Default::default(),
+                2,
+            )
+            .unwrap();
+        assert_eq!(completions[0].tokens, [28, 2]);
+        assert_eq!(completions[0].text, "z");
+        assert_eq!(completions[1].tokens, [5, 6]);
+        assert_eq!(session.tokens(), [1, 27, 28, 2]);
+        assert_eq!(MockModel::stored_token(&session, 3), Some(2));
+    }
+}
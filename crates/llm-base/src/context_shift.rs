@@ -0,0 +1,366 @@
+use ggml::{accelerator::Backend, Type};
+use half::f16;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::log;
+
+use crate::{InferenceError, InferenceSession, Model};
+
+/// Makes room in the context window of an [InferenceSession] when it fills up, by
+/// discarding the oldest tokens that follow the first `keep` tokens.
+///
+/// Half of the tokens after the first `keep` are discarded at a time, or more if that is
+/// not enough for the tokens being fed. The remaining tokens are moved back in the memory
+/// of the session, as if they had been evaluated without the discarded tokens; for models
+/// that use [RoPE](https://arxiv.org/abs/2104.09864), their keys are rotated to their new
+/// positions. The tokens that are kept are not evaluated again, so this is much faster
+/// than starting over, but the model may lose track of what was discarded.
+///
+/// This is set with [InferenceSessionConfig::context_shift](crate::InferenceSessionConfig::context_shift),
+/// and requires a model that describes its [memory layout](Model::kv_memory_layout).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextShift {
+    /// The number of tokens at the start of the session that are never discarded, such as
+    /// the beginning-of-text token and a system prompt.
+    pub keep: usize,
+}
+
+/// How a model stores each token in the memory of an [InferenceSession], which is needed
+/// to [shift its context](InferenceSession::shift_context).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KvMemoryLayout {
+    /// The number of layers, each of which stores every token.
+    pub n_layer: usize,
+    /// The number of elements that are stored for each token in each layer, in both the
+    /// key and value memory.
+    pub token_width: usize,
+    /// Whether the values of each layer are stored transposed, with the elements of a token
+    /// `context_size` apart, rather than next to each other like the keys.
+    pub transposed_values: bool,
+    /// The rotary position embedding that was applied to the stored keys, if any.
+    pub rope: Option<Rope>,
+}
+
+/// A rotary position embedding, as applied by [ggml::Context::op_rope].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rope {
+    /// The number of elements of each attention head in the stored keys.
+    pub head_dim: usize,
+    /// The number of dimensions that are rotated (the `ndims` of [ggml::Context::op_rope]).
+    pub n_dims: usize,
+    /// Whether each element is rotated with the one `n_dims / 2` after it, as in GPT-NeoX
+    /// (mode 2), rather than with the one next to it (mode 0).
+    pub neox: bool,
+    /// The base of the rotation frequencies, such as [ggml::ROPE_FREQ_BASE].
+    pub freq_base: f32,
+    /// The factor applied to each position before it is rotated, such as
+    /// [ggml::ROPE_FREQ_SCALE].
+    pub freq_scale: f32,
+}
+impl Rope {
+    /// Rotates the keys of one attention head by `delta` positions.
+    fn rotate(&self, head: &mut [f32], delta: f32) {
+        let theta_scale = self.freq_base.powf(-2.0 / self.n_dims as f32);
+        let rotate_pair = |head: &mut [f32], k: usize, i0: usize, i1: usize| {
+            let (sin, cos) = (self.freq_scale * delta * theta_scale.powi(k as i32)).sin_cos();
+            let (x0, x1) = (head[i0], head[i1]);
+            head[i0] = x0 * cos - x1 * sin;
+            head[i1] = x0 * sin + x1 * cos;
+        };
+
+        if self.neox {
+            // Like ggml, the angle keeps decreasing across the blocks of `n_dims`.
+            let half = self.n_dims / 2;
+            for block in 0..self.head_dim / self.n_dims {
+                for i in 0..half {
+                    let i0 = block * self.n_dims + i;
+                    rotate_pair(head, block * half + i, i0, i0 + half);
+                }
+            }
+        } else {
+            for i in 0..self.n_dims / 2 {
+                rotate_pair(head, i, 2 * i, 2 * i + 1);
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered when shifting the context of an [InferenceSession].
+pub enum ContextShiftError {
+    /// Tried discarding more tokens than were available
+    #[error("tried discarding more tokens than were available")]
+    NotEnoughTokens,
+
+    /// Model architecture does not describe its memory layout
+    #[error("model architecture does not support context shifting")]
+    UnsupportedArchitecture,
+
+    /// The memory of the session is not accessible from the CPU
+    #[error("the memory of the session is on an accelerator")]
+    UnsupportedBackend,
+}
+
+impl InferenceSession {
+    /// Discards `discard` tokens that follow the first `keep` tokens of this session, and
+    /// moves the tokens after them back to take their place.
+    ///
+    /// This is what [ContextShift] does when the context window fills up. It can also be
+    /// used to remove tokens that are no longer needed, such as an old turn of a
+    /// conversation. The [Self::last_logits] are kept.
+    pub fn shift_context(
+        &mut self,
+        model: &dyn Model,
+        keep: usize,
+        discard: usize,
+    ) -> Result<(), ContextShiftError> {
+        let layout = model
+            .kv_memory_layout()
+            .ok_or(ContextShiftError::UnsupportedArchitecture)?;
+        if self.memory_k.backend() != Backend::Cpu || self.memory_v.backend() != Backend::Cpu {
+            return Err(ContextShiftError::UnsupportedBackend);
+        }
+        if keep + discard > self.n_past {
+            return Err(ContextShiftError::NotEnoughTokens);
+        }
+        if discard == 0 {
+            return Ok(());
+        }
+
+        let shift = MemoryShift {
+            layout,
+            context_size: model.context_size(),
+            n_past: self.n_past,
+            keep,
+            discard,
+        };
+        // Safety: the memory is on the CPU, is only used by this session, and was
+        // allocated with an element type of `ModelKVMemoryType`.
+        unsafe {
+            shift.apply_to(&mut self.memory_k, true);
+            shift.apply_to(&mut self.memory_v, false);
+        }
+
+        self.n_past -= discard;
+        self.tokens.drain(keep..keep + discard);
+        self.redecode_tokens(model);
+
+        Ok(())
+    }
+
+    /// Makes room for `n_tokens` more tokens with the [ContextShift] of this session, if
+    /// they would not fit otherwise.
+    pub(crate) fn make_room(
+        &mut self,
+        model: &dyn Model,
+        n_tokens: usize,
+    ) -> Result<(), InferenceError> {
+        let context_size = model.context_size();
+        if self.n_past + n_tokens < context_size {
+            return Ok(());
+        }
+        let Some(ContextShift { keep }) = self.config.context_shift else {
+            return Err(InferenceError::ContextFull);
+        };
+
+        let discard =
+            (self.n_past.saturating_sub(keep) / 2).max(self.n_past + n_tokens + 1 - context_size);
+        if keep + discard > self.n_past {
+            return Err(InferenceError::ContextFull);
+        }
+        log::trace!("Shifting context, discarding {discard} tokens after the first {keep}");
+        Ok(self.shift_context(model, keep, discard)?)
+    }
+}
+
+/// The movement of the tokens in the memory of a session by [InferenceSession::shift_context].
+struct MemoryShift {
+    layout: KvMemoryLayout,
+    context_size: usize,
+    n_past: usize,
+    keep: usize,
+    discard: usize,
+}
+impl MemoryShift {
+    /// # Safety
+    ///
+    /// The `memory` must be on the CPU, must not be used by any other code, and must be
+    /// of [Type::F16] or [Type::F32].
+    unsafe fn apply_to(&self, memory: &mut ggml::Tensor, is_key: bool) {
+        let len = memory.nelements();
+        match memory.get_type() {
+            Type::F16 => self.apply(
+                std::slice::from_raw_parts_mut(memory.data() as *mut f16, len),
+                is_key,
+            ),
+            Type::F32 => self.apply(
+                std::slice::from_raw_parts_mut(memory.data() as *mut f32, len),
+                is_key,
+            ),
+            ty => unreachable!("the memory of a session can't be of type {ty:?}"),
+        }
+    }
+
+    fn apply<T: MemoryElement>(&self, memory: &mut [T], is_key: bool) {
+        let KvMemoryLayout {
+            n_layer,
+            token_width,
+            transposed_values,
+            rope,
+        } = self.layout;
+        let kept = self.keep..self.n_past - self.discard;
+        let moved = self.keep + self.discard..self.n_past;
+
+        for il in 0..n_layer {
+            let layer = il * self.context_size * token_width;
+            if !is_key && transposed_values {
+                for d in 0..token_width {
+                    let row = layer + d * self.context_size;
+                    memory.copy_within(row + moved.start..row + moved.end, row + kept.start);
+                }
+                continue;
+            }
+
+            memory.copy_within(
+                layer + moved.start * token_width..layer + moved.end * token_width,
+                layer + kept.start * token_width,
+            );
+
+            if let (true, Some(rope)) = (is_key, rope) {
+                let mut head = vec![0.0; rope.head_dim];
+                let tokens =
+                    &mut memory[layer + kept.start * token_width..layer + kept.end * token_width];
+                for stored in tokens.chunks_exact_mut(rope.head_dim) {
+                    for (h, s) in head.iter_mut().zip(stored.iter()) {
+                        *h = s.to_f32();
+                    }
+                    rope.rotate(&mut head, -(self.discard as f32));
+                    for (s, h) in stored.iter_mut().zip(&head) {
+                        *s = T::from_f32(*h);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An element of the memory of a session.
+trait MemoryElement: Copy {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+impl MemoryElement for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+impl MemoryElement for f16 {
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{a:?} != {b:?}");
+        }
+    }
+
+    #[test]
+    fn test_rope_rotations_compose() {
+        for neox in [false, true] {
+            let rope = Rope {
+                head_dim: 8,
+                n_dims: 4,
+                neox,
+                freq_base: ggml::ROPE_FREQ_BASE,
+                freq_scale: ggml::ROPE_FREQ_SCALE,
+            };
+            let original: Vec<f32> = (0..8).map(|i| i as f32 - 3.5).collect();
+
+            let mut shifted = original.clone();
+            rope.rotate(&mut shifted, 7.0);
+            rope.rotate(&mut shifted, -3.0);
+            let mut direct = original.clone();
+            rope.rotate(&mut direct, 4.0);
+            assert_close(&shifted, &direct);
+
+            rope.rotate(&mut shifted, -4.0);
+            assert_close(&shifted, &original);
+        }
+    }
+
+    #[test]
+    fn test_rope_uses_frequency_base_and_scale() {
+        for neox in [false, true] {
+            let rope = Rope {
+                head_dim: 8,
+                n_dims: 8,
+                neox,
+                freq_base: 500.0,
+                freq_scale: 0.25,
+            };
+            let original: Vec<f32> = (0..8).map(|i| i as f32 - 3.5).collect();
+
+            let mut scaled = original.clone();
+            rope.rotate(&mut scaled, 8.0);
+            let mut direct = original.clone();
+            Rope {
+                freq_scale: 1.0,
+                ..rope
+            }
+            .rotate(&mut direct, 2.0);
+            assert_close(&scaled, &direct);
+
+            let mut default = original.clone();
+            Rope {
+                freq_base: ggml::ROPE_FREQ_BASE,
+                ..rope
+            }
+            .rotate(&mut default, 8.0);
+            assert!((default[7] - scaled[7]).abs() > 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_memory_shift_moves_tokens() {
+        let shift = MemoryShift {
+            layout: KvMemoryLayout {
+                n_layer: 2,
+                token_width: 2,
+                transposed_values: true,
+                rope: None,
+            },
+            context_size: 5,
+            n_past: 5,
+            keep: 1,
+            discard: 2,
+        };
+
+        // Each element is 10 * layer + token.
+        let mut keys: Vec<f32> = (0..2)
+            .flat_map(|il| (0..5).flat_map(move |t| [(10 * il + t) as f32; 2]))
+            .collect();
+        shift.apply(&mut keys, true);
+        assert_eq!(&keys[..6], [0.0, 0.0, 3.0, 3.0, 4.0, 4.0]);
+        assert_eq!(&keys[10..16], [10.0, 10.0, 13.0, 13.0, 14.0, 14.0]);
+
+        let mut values: Vec<f32> = (0..2)
+            .flat_map(|il| (0..2).flat_map(move |_| (0..5).map(move |t| (10 * il + t) as f32)))
+            .collect();
+        shift.apply(&mut values, false);
+        for row in values.chunks(5) {
+            assert_eq!(&row[1..3], [row[0] + 3.0, row[0] + 4.0]);
+        }
+    }
+}
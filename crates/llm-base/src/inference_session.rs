@@ -1,6 +1,13 @@
-use ggml::{Buffer, ComputationGraph, Context, GraphExecutionPlan, Tensor};
+use ggml::{accelerator::Backend, Buffer, ComputationGraph, Context, GraphExecutionPlan, Tensor};
+use partial_sort::PartialSort;
 use serde::Serialize;
-use std::{cell::RefCell, fmt::Display, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    ops::Range,
+    sync::{Arc, Weak},
+};
 use thiserror::Error;
 use tracing::{instrument, log};
 
@@ -8,8 +15,14 @@ use tracing::{instrument, log};
 use ggml::accelerator::metal::MetalContext;
 
 use crate::{
-    mulf, util, InferenceParameters, Model, ModelParameters, OutputRequest, Prompt, TokenId,
-    TokenUtf8Buffer, TokenizationError,
+    mulf,
+    samplers::{
+        ConstrainedSampler, Grammar, GrammarConstraint, GrammarError, Sampler, SamplerState,
+    },
+    speculative::Drafter,
+    util::{self, BannedPhraseBuffer, BannedPhraseOutcome, StopSequenceBuffer},
+    ContextShift, ContextShiftError, InferenceParameters, Model, ModelParameters, OutputRequest,
+    Prompt, TokenId, TokenUtf8Buffer, TokenizationError,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
@@ -83,8 +96,11 @@ pub struct InferenceSession {
     #[doc(hidden)]
     pub last_logits: Vec<f32>,
 
+    // The per-session state of the sampler that was last used.
+    pub(crate) sampler_state: SessionSamplerState,
+
     #[cfg(feature = "metal")]
-    metal_context: Option<MetalContext>,
+    pub(crate) metal_context: Option<MetalContext>,
 
     ctx0: Context,
 
@@ -93,21 +109,57 @@ pub struct InferenceSession {
     scratch: ScratchBuffers,
 }
 
+/// The context in which a model builds its graph, passed to the builder of
+/// [InferenceSession::compute] and [InferenceSession::compute_batch].
 pub struct BuildContext<'session> {
     //FIXME: Borrowing issue, dont know how to fix it
+    /// The context that the graph is built in.
     pub ctx0: RefCell<&'session mut Context>,
+    /// The input tokens of all of the sequences, one after the other.
     pub embd: &'session Tensor,
+    /// The key memory of the first sequence.
     pub memory_k: &'session Tensor,
+    /// The value memory of the first sequence.
     pub memory_v: &'session Tensor,
+    /// The scratch buffers for intermediate results.
     pub scratch: &'session ScratchBuffers,
+    /// The sequences that are being evaluated, of which there is only one when building
+    /// the graph for [InferenceSession::compute].
+    pub sequences: Vec<SequenceMemory<'session>>,
 }
 
 impl<'session> BuildContext<'session> {
+    /// Returns the scratch buffer with the index `idx`.
     pub fn get_scratch(&self, idx: usize) -> Option<&Buffer> {
         Some(&self.scratch[idx])
     }
 }
 
+/// The memory and the input tokens of one of the sequences of a [BuildContext].
+#[derive(Clone, Copy)]
+pub struct SequenceMemory<'session> {
+    /// The key memory of the session of this sequence.
+    pub memory_k: &'session Tensor,
+    /// The value memory of the session of this sequence.
+    pub memory_v: &'session Tensor,
+    /// The number of tokens that the session of this sequence has already evaluated.
+    pub n_past: usize,
+    /// The index of the first input token of this sequence in [BuildContext::embd].
+    pub input_offset: usize,
+    /// The number of input tokens of this sequence.
+    pub input_len: usize,
+}
+
+/// One of the sequences that are evaluated together by [Model::evaluate_batch].
+pub struct BatchSequence<'a> {
+    /// The session that evaluates the tokens.
+    pub session: &'a mut InferenceSession,
+    /// The tokens to evaluate, of which there must be at least one.
+    pub input_tokens: &'a [TokenId],
+    /// The additional data to fetch for this sequence.
+    pub output_request: &'a mut OutputRequest,
+}
+
 unsafe impl Send for InferenceSession {}
 impl InferenceSession {
     /// Create a new InferenceSession
@@ -204,6 +256,7 @@ impl InferenceSession {
             tokens: vec![],
             decoded_tokens: vec![],
             last_logits: vec![0.0; n_vocab],
+            sampler_state: Default::default(),
             #[cfg(feature = "metal")]
             metal_context,
             ctx0,
@@ -235,6 +288,13 @@ impl InferenceSession {
             memory_k: &self.memory_k,
             memory_v: &self.memory_v,
             scratch: &mut self.scratch,
+            sequences: vec![SequenceMemory {
+                memory_k: &self.memory_k,
+                memory_v: &self.memory_v,
+                n_past: self.n_past,
+                input_offset: 0,
+                input_len: input_tokens.len(),
+            }],
         };
         let (mut built_gf, built_result) = builder(bc);
 
@@ -290,6 +350,92 @@ impl InferenceSession {
         }
     }
 
+    /// Computes one graph that evaluates the input tokens of all of the sequences of the
+    /// `batch`, for [Model::evaluate_batch].
+    ///
+    /// The graph is built in the context of the first session, and is computed on the CPU
+    /// with its number of threads, so the `batch` must be small enough for the buffers of
+    /// that session, and the memory of every session must be on the CPU; the batches given
+    /// to [Model::evaluate_batch] can be split with
+    /// [common::evaluate_in_parts](crate::model::common::evaluate_in_parts). The `builder`
+    /// must read the memory and the number of past tokens of each sequence from
+    /// [BuildContext::sequences]. Unlike [Self::compute], this does not measure
+    /// [Self::mem_per_token], as the graph is for several sessions.
+    pub fn compute_batch<F>(
+        batch: &mut [BatchSequence],
+        #[allow(unused_variables)] model_context: Arc<Context>,
+        builder: F,
+    ) -> GraphOutputs
+    where
+        F: FnOnce(BuildContext) -> (ComputationGraph, GraphOutputs),
+    {
+        let input_tokens: Vec<TokenId> = batch
+            .iter()
+            .flat_map(|sequence| sequence.input_tokens)
+            .copied()
+            .collect();
+        let (first, rest) = batch
+            .split_first_mut()
+            .expect("a batch must have at least one sequence");
+        let first_len = first.input_tokens.len();
+        let first = &mut *first.session;
+
+        // Build a graph
+        first.ctx0.recreate();
+        let ctx0 = &mut first.ctx0;
+        let mut embd = ctx0
+            .new_tensor_1d(ggml::Type::I32, input_tokens.len())
+            .set_name("embd");
+
+        let mut sequences = vec![SequenceMemory {
+            memory_k: &first.memory_k,
+            memory_v: &first.memory_v,
+            n_past: first.n_past,
+            input_offset: 0,
+            input_len: first_len,
+        }];
+        sequences.extend(rest.iter().map(|sequence| SequenceMemory {
+            memory_k: &sequence.session.memory_k,
+            memory_v: &sequence.session.memory_v,
+            n_past: sequence.session.n_past,
+            input_offset: 0,
+            input_len: sequence.input_tokens.len(),
+        }));
+        let mut input_offset = 0;
+        for sequence in &mut sequences {
+            sequence.input_offset = input_offset;
+            input_offset += sequence.input_len;
+        }
+
+        let bc = BuildContext {
+            ctx0: RefCell::new(ctx0),
+            embd: &embd,
+            memory_k: &first.memory_k,
+            memory_v: &first.memory_v,
+            scratch: &first.scratch,
+            sequences,
+        };
+        let (mut built_gf, built_result) = builder(bc);
+
+        // Write input tokens
+        unsafe { embd.write_data(bytemuck::cast_slice(&input_tokens)) };
+
+        // Compute the graph
+        built_gf.build_forward_expand(&built_result.result);
+        let mut plan = GraphExecutionPlan::new(&mut built_gf, first.config.n_threads);
+        plan.execute(ctx0);
+
+        // Adjust n_past of each session to its new length.
+        for sequence in batch.iter_mut() {
+            sequence.session.n_past += sequence.input_tokens.len();
+        }
+
+        GraphOutputs {
+            result: built_result.result.share(),
+            embedding_result: built_result.embedding_result.share(),
+        }
+    }
+
     /// Feed a prompt to the model for this session.
     #[instrument(skip_all)]
     pub fn feed_prompt<'a, E: std::error::Error + Send + Sync + 'static, P: Into<Prompt<'a>>>(
@@ -297,16 +443,27 @@ impl InferenceSession {
         model: &dyn Model,
         prompt: P,
         output_request: &mut OutputRequest,
-        mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
     ) -> Result<(), InferenceError> {
         let beginning_of_sentence = self.n_past == 0;
 
         let vocab = model.tokenizer();
         let prompt_tokens = prompt.into().to_tokens(vocab, beginning_of_sentence)?;
 
-        if self.n_past + prompt_tokens.len() >= model.context_size() {
-            return Err(InferenceError::ContextFull);
-        }
+        self.feed_tokens(model, &prompt_tokens, output_request, callback)
+    }
+
+    /// Feeds `tokens` to the model like [Self::feed_prompt], without checking that they
+    /// have text, as the tokenizer gives none for control tokens such as the beginning and
+    /// end of text. This is for tokens that came from the tokenizer or the model.
+    pub(crate) fn feed_tokens<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        prompt_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+        mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        self.make_room(model, prompt_tokens.len())?;
 
         for batch in prompt_tokens.chunks(self.config.n_batch) {
             model.evaluate(self, batch, output_request);
@@ -372,7 +529,114 @@ impl InferenceSession {
         Ok(deleted_tokens)
     }
 
+    /// Feeds `prompt` as the whole text of this session, re-using the tokens that were
+    /// already evaluated.
+    ///
+    /// The tokens of the prompt are compared with [Self::tokens], and the session is rewound
+    /// to the first token that differs, so that only the rest of the prompt is evaluated.
+    /// This is useful when a prompt is submitted again with small changes, such as after
+    /// editing it. If the session has more tokens than the prompt, the last token of the
+    /// prompt is evaluated again to compute its logits, and if it has exactly the tokens of
+    /// the prompt, nothing is evaluated. The `callback` is only called for the evaluated
+    /// tokens.
+    ///
+    /// Unless the tokens of this session are all at the start of the prompt, the model must
+    /// [support rewinding](Model::supports_rewind).
+    #[instrument(skip_all)]
+    pub fn feed_prompt_incremental<
+        'a,
+        E: std::error::Error + Send + Sync + 'static,
+        P: Into<Prompt<'a>>,
+    >(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        let tokens = prompt.into().to_tokens(model.tokenizer(), true)?;
+        self.feed_tokens_incremental(model, &tokens, output_request, callback)
+    }
+
+    /// Rewinds and feeds this session so that its tokens are `tokens`, re-using the
+    /// longest common prefix, and so that [Self::last_logits] are for the last of them.
+    pub(crate) fn sync_tokens(
+        &mut self,
+        model: &dyn Model,
+        tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) -> Result<(), InferenceError> {
+        self.feed_tokens_incremental(model, tokens, output_request, |_| {
+            Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+        })
+    }
+
+    /// Like [Self::feed_prompt_incremental], with tokens that are fed with
+    /// [Self::feed_tokens].
+    fn feed_tokens_incremental<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        let mut common = self
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if common == self.tokens.len() && common == tokens.len() {
+            return Ok(());
+        }
+        if common == tokens.len() {
+            // The logits for the last token were overwritten, so it needs to be evaluated again.
+            common = common.saturating_sub(1);
+        }
+        log::trace!(
+            "Re-using {common} of {} prompt tokens, rewinding {}",
+            tokens.len(),
+            self.tokens.len() - common
+        );
+
+        if common == 0 {
+            // Nothing can be re-used, and rewinding can't remove every token.
+            self.clear();
+        } else if common < self.tokens.len() {
+            self.rewind(model, self.tokens.len() - common)?;
+        }
+        self.feed_tokens(model, &tokens[common..], output_request, callback)
+    }
+
+    /// Replaces the decoded text of this session with the text of all of its tokens, after
+    /// they were changed other than at the end.
+    pub(crate) fn redecode_tokens(&mut self, model: &dyn Model) {
+        self.decoded_tokens = match model.tokenizer() {
+            crate::Tokenizer::Embedded(_) => model.tokenizer().decode(self.tokens.clone(), false),
+            crate::Tokenizer::HuggingFace(_) => model.tokenizer().decode(self.tokens.clone(), true),
+        };
+    }
+
+    /// Adds `token` to the end of this session's tokens without evaluating it, and returns
+    /// its text.
+    pub(crate) fn push_token(&mut self, model: &dyn Model, token: TokenId) -> Vec<u8> {
+        self.tokens.push(token);
+        let bytes = match model.tokenizer() {
+            crate::Tokenizer::Embedded(_) => model.tokenizer().token(token as usize).to_vec(),
+            crate::Tokenizer::HuggingFace(_) => get_newly_decoded_portion_huggingface(
+                model,
+                self.tokens.clone(),
+                &self.decoded_tokens,
+            ),
+        };
+        self.decoded_tokens.extend_from_slice(&bytes);
+        bytes
+    }
+
     /// Infer the next token for this session.
+    ///
+    /// If the sampler keeps state between tokens (see [Sampler::new_state]), that state is
+    /// held by this session, and is kept for as long as the same sampler is used.
     #[instrument(level = "trace", skip_all)]
     pub fn infer_next_token(
         &mut self,
@@ -381,11 +645,11 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
     ) -> Result<Vec<u8>, InferenceError> {
-        if self.n_past + 1 >= model.context_size() {
-            return Err(InferenceError::ContextFull);
-        }
+        self.make_room(model, 1)?;
 
-        let next_token = params.sampler.sample(&self.tokens, &self.last_logits, rng);
+        let next_token =
+            self.sampler_state
+                .sample(&params.sampler, &self.tokens, &self.last_logits, rng);
 
         // Update the tokens for this session
         self.tokens.push(next_token);
@@ -413,6 +677,28 @@ impl InferenceSession {
         }
     }
 
+    /// Infers the next token like [Self::infer_next_token], but also returns the end-of-text
+    /// token rather than an error.
+    pub(crate) fn generate_next_token(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        keep_logits: bool,
+        rng: &mut impl rand::Rng,
+    ) -> Result<GeneratedToken, InferenceError> {
+        let logits = keep_logits.then(|| self.last_logits.clone());
+        let bytes = match self.infer_next_token(model, params, &mut Default::default(), rng) {
+            Ok(bytes) => bytes,
+            Err(InferenceError::EndOfText) => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(GeneratedToken {
+            id: *self.tokens.last().unwrap(),
+            bytes,
+            logits,
+        })
+    }
+
     /// Generate text by using the provided [Model] to evaluate the `prompt`.
     ///
     /// The `callback` is called with each new token until an end-of-text (EOT)
@@ -420,6 +706,7 @@ impl InferenceSession {
     /// generated (specified by [InferenceRequest::maximum_token_count]).
     ///
     /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
+    /// Each call starts with a fresh sampler state; see [Self::reset_sampler_state].
     #[instrument(skip_all)]
     pub fn infer<E: std::error::Error + Send + Sync + 'static>(
         &mut self,
@@ -427,8 +714,25 @@ impl InferenceSession {
         rng: &mut impl rand::Rng,
         request: &InferenceRequest,
         output_request: &mut OutputRequest,
+        callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
+    ) -> Result<InferenceStats, InferenceError> {
+        self.infer_with_drafter(model, None, rng, request, output_request, callback)
+    }
+
+    /// Implements [Self::infer], generating several tokens at a time if there is a `drafter`.
+    pub(crate) fn infer_with_drafter<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        mut drafter: Option<&mut dyn Drafter>,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
         mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
     ) -> Result<InferenceStats, InferenceError> {
+        if !request.banned_phrases.is_empty() && !model.supports_rewind() {
+            return Err(RewindError::UnsupportedArchitecture.into());
+        }
+
         let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
         if request.play_back_previous_tokens {
             // "Play back" the existing tokens, so that loading from an inference snapshot works
@@ -453,6 +757,8 @@ impl InferenceSession {
         let mut stats = InferenceStats::default();
         let start_at = std::time::SystemTime::now();
 
+        self.reset_sampler_state();
+
         let parameters = request.parameters;
 
         // Feed the initial prompt through the transformer, to update its
@@ -472,28 +778,141 @@ impl InferenceSession {
         // `infer_next_token`. We generate tokens until the model returns an
         // EndOfText token, or we run out of space in the context window,
         // or we reach the specified limit.
-        let mut tokens_processed = 0;
+        let eot = model.eot_token_id();
         let mut token_utf8_buf = TokenUtf8Buffer::new();
-        while tokens_processed < maximum_token_count {
-            let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
-            {
-                Ok(token) => token,
-                Err(InferenceError::EndOfText) => break,
-                Err(e) => return Err(e),
+        let mut stop_sequence_buf = StopSequenceBuffer::new(request.stop_sequences);
+        let mut generated_len = 0;
+        // Passes a generated token on to the callback. Returns `Some(halted)` if generation
+        // has ended, where `halted` is whether it was ended early.
+        let mut emit = |token: GeneratedToken| -> Result<Option<bool>, InferenceError> {
+            let text_range = generated_len..generated_len + token.bytes.len();
+            generated_len = text_range.end;
+            if let (Some(logits), Some(alternatives)) = (&token.logits, request.token_logprobs) {
+                let log_probs = util::log_softmax(logits);
+                let logprobs = TokenLogprobs::new(token.id, text_range, &log_probs, alternatives);
+                match callback(InferenceResponse::TokenLogprobs(logprobs)) {
+                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                    Ok(InferenceFeedback::Continue) => (),
+                    Ok(InferenceFeedback::Halt) => return Ok(Some(true)),
+                }
+            }
+
+            if token.id == eot {
+                return Ok(Some(false));
+            }
+
+            // Buffer the token until it's valid UTF-8 and can't be part of a stop sequence,
+            // then call the callback.
+            if let Some(tokens) = token_utf8_buf.push(&token.bytes) {
+                let (text, stopped) = stop_sequence_buf.push(&tokens);
+                if !text.is_empty() {
+                    match callback(InferenceResponse::InferredToken(text)) {
+                        Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                        Ok(InferenceFeedback::Continue) => (),
+                        Ok(InferenceFeedback::Halt) => return Ok(Some(true)),
+                    }
+                }
+                if stopped {
+                    return Ok(Some(true));
+                }
+            }
+            Ok(None)
+        };
+
+        // Tokens are held back until they can't be part of a banned phrase. If one is
+        // completed, the session is rewound to the token that it starts with, which is
+        // then banned at that position.
+        let mut banned_phrase_buf = BannedPhraseBuffer::new(request.banned_phrases);
+        let mut bans: HashMap<usize, Vec<TokenId>> = HashMap::new();
+
+        let mut tokens_processed = 0;
+        let mut ended = None;
+        'generate: while tokens_processed < maximum_token_count {
+            if let Some(bans) = bans.get(&self.tokens.len()) {
+                for &token in bans {
+                    self.last_logits[token as usize] = f32::NEG_INFINITY;
+                }
+            }
+            // If every token but the end-of-text token is banned, generation ends, as the
+            // sampler could be left without any candidates.
+            let exhausted = self
+                .last_logits
+                .iter()
+                .enumerate()
+                .all(|(id, &logit)| id == eot as usize || logit == f32::NEG_INFINITY);
+
+            let keep_logits = request.token_logprobs.is_some();
+            let generated = match drafter.as_deref_mut() {
+                _ if exhausted => {
+                    let logits = keep_logits.then(|| self.last_logits.clone());
+                    self.make_room(model, 1)?;
+                    self.tokens.push(eot);
+                    model.evaluate(self, &[eot], &mut Default::default());
+                    vec![GeneratedToken {
+                        id: eot,
+                        bytes: vec![],
+                        logits,
+                    }]
+                }
+                Some(drafter) => self.infer_next_tokens_speculative(
+                    model,
+                    drafter,
+                    parameters,
+                    maximum_token_count - tokens_processed,
+                    keep_logits,
+                    rng,
+                )?,
+                None => vec![self.generate_next_token(model, parameters, keep_logits, rng)?],
             };
 
-            // Buffer the token until it's valid UTF-8, then call the callback.
-            if let Some(tokens) = token_utf8_buf.push(&token) {
-                match callback(InferenceResponse::InferredToken(tokens)) {
-                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
-                    Ok(f) => match f {
-                        InferenceFeedback::Continue => (),
-                        InferenceFeedback::Halt => break,
-                    },
+            let mut remaining = generated.len();
+            for token in generated {
+                remaining -= 1;
+                let released = if token.id == eot {
+                    let mut released = banned_phrase_buf.flush();
+                    released.push(token);
+                    released
+                } else {
+                    tokens_processed += 1;
+                    match banned_phrase_buf.push(token.id, token, |token| &token.bytes) {
+                        BannedPhraseOutcome::Released(released) => released,
+                        BannedPhraseOutcome::Banned { rewind, token } => {
+                            let position = self.tokens.len() - remaining - rewind;
+                            bans.retain(|&p, _| p <= position);
+                            bans.entry(position).or_default().push(token);
+
+                            let tokens = self.tokens[..position].to_vec();
+                            self.sync_tokens(model, &tokens, &mut OutputRequest::default())?;
+                            tokens_processed -= rewind;
+                            continue 'generate;
+                        }
+                    }
+                };
+
+                for token in released {
+                    ended = emit(token)?;
+                    if ended.is_some() {
+                        break 'generate;
+                    }
                 }
             }
+        }
 
-            tokens_processed += 1;
+        // Generation ended without completing a banned phrase or a stop sequence, so the
+        // tokens and text that were held back are part of the output.
+        if ended.is_none() {
+            for token in banned_phrase_buf.flush() {
+                ended = emit(token)?;
+                if ended.is_some() {
+                    break;
+                }
+            }
+        }
+        let remaining = stop_sequence_buf.flush();
+        if ended != Some(true) && !remaining.is_empty() {
+            if let Err(e) = callback(InferenceResponse::InferredToken(remaining)) {
+                return Err(InferenceError::UserCallback(Box::new(e)));
+            }
         }
         stats.predict_duration = start_at.elapsed().unwrap();
         stats.predict_tokens = self.n_past;
@@ -501,6 +920,55 @@ impl InferenceSession {
         Ok(stats)
     }
 
+    /// Generate a JSON value that conforms to the JSON `schema`, and deserialize it into `T`.
+    ///
+    /// This behaves like [Self::infer], except that the sampler from the `request` is
+    /// constrained to the schema, as described by [Grammar::from_json_schema]. The prompt
+    /// should ask for the value to be generated, as the model will otherwise have to guess
+    /// what to fill it with.
+    ///
+    /// If generation stops before the value is complete, such as when the
+    /// [InferenceRequest::maximum_token_count] is reached, deserialization will fail.
+    pub fn infer_json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
+        schema: &serde_json::Value,
+    ) -> Result<T, JsonInferenceError> {
+        let constraint = GrammarConstraint::new(
+            Grammar::from_json_schema(schema)?,
+            model.tokenizer(),
+            model.eot_token_id(),
+        );
+        let parameters = InferenceParameters {
+            sampler: Arc::new(ConstrainedSampler::new(
+                constraint,
+                request.parameters.sampler.clone(),
+            )),
+        };
+
+        let mut json = String::new();
+        self.infer::<std::convert::Infallible>(
+            model,
+            rng,
+            &InferenceRequest {
+                parameters: &parameters,
+                ..*request
+            },
+            output_request,
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    json.push_str(&token);
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        )?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
     /// Calculate perplexity over a given prompt, with a value reported for each
     /// chunk that has been processed.
     ///
@@ -581,12 +1049,11 @@ impl InferenceSession {
     /// Obtains a serializable snapshot of the current inference status. This
     /// can be used to cache the state of the model and store them into a file.
     ///
-    /// # Safety
-    ///
-    /// This function provides raw access to the underlying memory owned by the
-    /// ggml context. While the provided `InferenceSnapshotRef` object is alive,
-    /// no other methods for this model object should be called.
-    pub unsafe fn get_snapshot(&mut self) -> InferenceSnapshotRef<'_> {
+    /// The snapshot borrows the memory of the session, which is why the session is
+    /// borrowed mutably until the snapshot is dropped.
+    pub fn snapshot(&mut self) -> InferenceSnapshotRef<'_> {
+        // SAFETY: The session is borrowed mutably for the lifetime of the snapshot, so
+        // the memory can't be written to while it is alive.
         let memory_k = unsafe {
             std::slice::from_raw_parts(self.memory_k.data() as *mut u8, self.memory_k.nbytes())
         };
@@ -604,6 +1071,14 @@ impl InferenceSession {
         }
     }
 
+    /// Obtains a serializable snapshot of the current inference status.
+    ///
+    /// This is the same as [Self::snapshot], which it has been replaced by.
+    #[deprecated(note = "use `InferenceSession::snapshot` instead")]
+    pub fn get_snapshot(&mut self) -> InferenceSnapshotRef<'_> {
+        self.snapshot()
+    }
+
     /// Creates an [InferenceSession] from a snapshot.
     pub fn from_snapshot(
         snapshot: InferenceSnapshot,
@@ -635,6 +1110,92 @@ impl InferenceSession {
         Ok(session)
     }
 
+    /// Creates an independent copy of this session, with its own copy of the memory,
+    /// tokens and logits, that can continue from the same point without affecting it.
+    ///
+    /// This is cheaper than a round-trip through [Self::snapshot] and
+    /// [Self::from_snapshot], as the memory is copied directly. The state of the sampler
+    /// is not copied, so the first token sampled by the new session is sampled as if by
+    /// a new sampler.
+    ///
+    /// `model` must be the model that this session was started with. This fails if the
+    /// memory of this session is on an accelerator, rather than the CPU.
+    pub fn fork(&self, model: &dyn Model) -> Result<Self, SnapshotError> {
+        if self.memory_k.backend() != Backend::Cpu || self.memory_v.backend() != Backend::Cpu {
+            return Err(SnapshotError::UnsupportedBackend);
+        }
+
+        let mut session = model.start_session(self.config);
+        session.copy_from(self)?;
+        Ok(session)
+    }
+
+    /// Replaces the state of this session with a copy of the state of `other`, a session
+    /// of the same model, as if this session was made with [Self::fork]. This avoids
+    /// allocating a new session when one is no longer needed.
+    pub(crate) fn copy_from(&mut self, other: &Self) -> Result<(), SnapshotError> {
+        let on_cpu = |session: &Self| {
+            session.memory_k.backend() == Backend::Cpu && session.memory_v.backend() == Backend::Cpu
+        };
+        if !on_cpu(self) || !on_cpu(other) {
+            return Err(SnapshotError::UnsupportedBackend);
+        }
+        if self.memory_k.nbytes() != other.memory_k.nbytes()
+            || self.memory_v.nbytes() != other.memory_v.nbytes()
+        {
+            return Err(SnapshotError::MemorySizeMismatch {
+                self_size: self.memory_k.nbytes() + self.memory_v.nbytes(),
+                input_size: other.memory_k.nbytes() + other.memory_v.nbytes(),
+            });
+        }
+
+        // SAFETY: The memory of both sessions is on the CPU. We have exclusive access to
+        // this session and shared access to the other, so neither memory is being written
+        // to, and both have the same size.
+        unsafe {
+            let memory_k = std::slice::from_raw_parts_mut(
+                self.memory_k.data() as *mut u8,
+                self.memory_k.nbytes(),
+            );
+            other.memory_k.read_data(0, memory_k);
+            let memory_v = std::slice::from_raw_parts_mut(
+                self.memory_v.data() as *mut u8,
+                self.memory_v.nbytes(),
+            );
+            other.memory_v.read_data(0, memory_v);
+        }
+
+        self.config = other.config;
+        self.n_past = other.n_past;
+        self.mem_per_token = other.mem_per_token;
+        self.tokens.clone_from(&other.tokens);
+        self.decoded_tokens.clone_from(&other.decoded_tokens);
+        self.last_logits.clone_from(&other.last_logits);
+        self.reset_sampler_state();
+        Ok(())
+    }
+
+    /// Sets how to make room for more tokens when the context window is full, as with
+    /// [InferenceSessionConfig::context_shift].
+    pub fn set_context_shift(&mut self, context_shift: Option<ContextShift>) {
+        self.config.context_shift = context_shift;
+    }
+
+    /// Discards the per-session state of the sampler, so that the next token is sampled
+    /// as if it were the first. This has no effect on stateless samplers.
+    pub fn reset_sampler_state(&mut self) {
+        self.sampler_state = Default::default();
+    }
+
+    /// Removes all of the tokens of this session, so that it can be used as if it was new.
+    pub(crate) fn clear(&mut self) {
+        self.n_past = 0;
+        self.tokens.clear();
+        self.decoded_tokens.clear();
+        self.last_logits.fill(0.0);
+        self.reset_sampler_state();
+    }
+
     /// All tokens generated by this inference session
     pub fn tokens(&self) -> &[TokenId] {
         self.tokens.as_ref()
@@ -654,6 +1215,36 @@ impl Drop for InferenceSession {
     }
 }
 
+/// The state of the sampler that was last used by an [InferenceSession].
+#[derive(Default)]
+pub(crate) struct SessionSamplerState {
+    // The sampler that the state belongs to. This is held weakly so that the sampler is not
+    // kept alive by the session, while still preventing its address from being reused.
+    sampler: Option<Weak<dyn Sampler>>,
+    state: SamplerState,
+}
+impl SessionSamplerState {
+    /// Samples a token with `sampler`, creating new state for it if it is not the sampler
+    /// that was used last.
+    pub(crate) fn sample(
+        &mut self,
+        sampler: &Arc<dyn Sampler>,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let is_same_sampler = self.sampler.as_ref().map_or(false, |s| {
+            std::ptr::eq(s.as_ptr() as *const (), Arc::as_ptr(sampler) as *const ())
+        });
+        if !is_same_sampler {
+            self.sampler = Some(Arc::downgrade(sampler));
+            self.state = sampler.new_state();
+        }
+
+        sampler.sample_with_state(&mut self.state, previous_tokens, logits, rng)
+    }
+}
+
 fn get_newly_decoded_portion_huggingface(
     model: &dyn Model,
     tokens: Vec<u32>,
@@ -684,11 +1275,37 @@ pub enum InferenceError {
     ///
     /// Note that this error *can* be ignored and inference can continue, but the results are not guaranteed to be sensical.
     EndOfText,
+    #[error("the session could not be rewound")]
+    /// The session needed to be rewound, but this failed; for example, because the
+    /// model does not support rewinding.
+    RewindFailed(#[from] RewindError),
+    #[error("the context window could not be shifted")]
+    /// The context window was full, and shifting it with the [ContextShift] of the
+    /// session failed; for example, because the model does not support it.
+    ContextShiftFailed(#[from] ContextShiftError),
+    #[error("the session could not be copied")]
+    /// The session needed to be copied with [InferenceSession::fork], but this failed; for
+    /// example, because its memory is on an accelerator.
+    ForkFailed(#[from] SnapshotError),
     #[error("the user-specified callback returned an error")]
     /// The user-specified callback returned an error.
     UserCallback(Box<dyn std::error::Error + Send + Sync>),
 }
 
+#[derive(Error, Debug)]
+/// Errors encountered when generating JSON with [InferenceSession::infer_json].
+pub enum JsonInferenceError {
+    #[error("the JSON schema could not be used to constrain generation")]
+    /// The JSON schema could not be converted into a grammar.
+    Schema(#[from] GrammarError),
+    #[error("inference failed")]
+    /// Inference failed.
+    Inference(#[from] InferenceError),
+    #[error("the generated JSON could not be deserialized")]
+    /// The generated JSON could not be deserialized into the requested type.
+    Deserialization(#[from] serde_json::Error),
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the snapshot process.
 pub enum RewindError {
@@ -715,11 +1332,14 @@ pub enum SnapshotError {
         /// The size of the session memory in snapshot.
         input_size: usize,
     },
+    /// The memory of the session is not accessible from the CPU.
+    #[error("the memory of the session is on an accelerator")]
+    UnsupportedBackend,
 }
 
 #[derive(serde::Serialize, Clone, PartialEq)]
 /// A serializable snapshot of the inference process.
-/// Can be created by calling [InferenceSession::get_snapshot].
+/// Can be created by calling [InferenceSession::snapshot].
 ///
 /// If serializing, ensure that your serializer is binary-efficient.
 /// This type contains a large array of bytes; traditional textual serializers
@@ -814,6 +1434,13 @@ pub struct InferenceSessionConfig {
     /// A reasonable default value is 8, as most modern high-performance computers have
     /// 8 physical cores. Adjust to your needs.
     pub n_threads: usize,
+    /// How to make room for more tokens when the context window is full. If `None`,
+    /// inference fails with [InferenceError::ContextFull] instead.
+    ///
+    /// This is not stored in [snapshots](InferenceSnapshot), so that older snapshots can
+    /// still be read; set it with [InferenceSession::set_context_shift] after restoring one.
+    #[serde(skip)]
+    pub context_shift: Option<ContextShift>,
 }
 
 impl Default for InferenceSessionConfig {
@@ -823,6 +1450,7 @@ impl Default for InferenceSessionConfig {
             memory_v_type: ModelKVMemoryType::Float16,
             n_batch: 8,
             n_threads: 8,
+            context_shift: None,
         }
     }
 }
@@ -842,6 +1470,51 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// Sequences of text that end generation when they are generated.
+    ///
+    /// These are matched against the generated text, so they are found regardless of
+    /// how they are split into tokens. Text that could be the start of a stop sequence
+    /// is held back from the callback until it is known not to be one, and the stop
+    /// sequence itself is never passed to the callback. It is still part of the session.
+    pub stop_sequences: &'a [String],
+    /// Phrases that must not be generated.
+    ///
+    /// These are matched against the generated text, like [Self::stop_sequences]. When
+    /// one is completed, the session is rewound to the token that the phrase starts in,
+    /// and that token is banned at that position; if every token but the end-of-text token
+    /// is banned there, generation ends. Text that could be part of a banned
+    /// phrase is held back from the callback until it is known not to be one. This
+    /// requires the model to [support rewinding](Model::supports_rewind).
+    pub banned_phrases: &'a [String],
+    /// If set, the callback is sent an [InferenceResponse::TokenLogprobs] for each
+    /// generated token, with this many of the most likely alternatives to it.
+    pub token_logprobs: Option<usize>,
+}
+impl<'a> InferenceRequest<'a> {
+    /// Creates a request to feed the `prompt` and then generate text with the
+    /// `parameters`, without a token limit or any of the optional settings.
+    ///
+    /// The other fields can be set with the struct update syntax:
+    ///
+    /// ```
+    /// # use llm_base::{InferenceParameters, InferenceRequest};
+    /// let parameters = InferenceParameters::default();
+    /// let request = InferenceRequest {
+    ///     maximum_token_count: Some(32),
+    ///     ..InferenceRequest::new("Rust is".into(), &parameters)
+    /// };
+    /// ```
+    pub fn new(prompt: Prompt<'a>, parameters: &'a InferenceParameters) -> Self {
+        Self {
+            prompt,
+            parameters,
+            play_back_previous_tokens: false,
+            maximum_token_count: None,
+            stop_sequences: &[],
+            banned_phrases: &[],
+            token_logprobs: None,
+        }
+    }
 }
 
 /// Statistics about the inference process.
@@ -919,6 +1592,69 @@ pub enum InferenceResponse {
     InferredToken(String),
     /// The inference session has generated an end-of-text token
     EotToken,
+    /// The log-probability of a token that has been generated, and of its most likely
+    /// alternatives. Only sent if [InferenceRequest::token_logprobs] is set.
+    ///
+    /// This is sent as soon as the token is generated, including the end-of-text token,
+    /// so its text may be sent later, or not at all if it is part of a stop sequence.
+    /// Such tokens can be told apart with [TokenLogprobs::text_range].
+    TokenLogprobs(TokenLogprobs),
+}
+
+/// A token generated by a single step of [InferenceSession::infer].
+pub(crate) struct GeneratedToken {
+    pub id: TokenId,
+    /// The text of the token, which is empty for the end-of-text token.
+    pub bytes: Vec<u8>,
+    /// The logits that the token was sampled from, if they were requested.
+    pub logits: Option<Vec<f32>>,
+}
+
+/// The log-probabilities of a generated token and its alternatives, as reported by
+/// [InferenceResponse::TokenLogprobs].
+///
+/// These come from the distribution predicted by the model, before it was changed
+/// by the sampler, so they are not affected by the temperature or penalties.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprobs {
+    /// The generated token.
+    pub token: TokenId,
+    /// The bytes of the generated text that are the text of this token, where the generated
+    /// text is that of every token generated by this call to [InferenceSession::infer].
+    ///
+    /// This includes any stop sequence, which is not sent to the callback, so a token whose
+    /// range ends after the text that was sent with [InferenceResponse::InferredToken] is
+    /// part of the stop sequence. The end-of-text token has an empty range.
+    pub text_range: Range<usize>,
+    /// The log-probability of the generated token.
+    pub logprob: f32,
+    /// The most likely tokens and their log-probabilities, from most to least likely.
+    /// These may include the generated token.
+    pub top_logprobs: Vec<(TokenId, f32)>,
+}
+impl TokenLogprobs {
+    fn new(
+        token: TokenId,
+        text_range: Range<usize>,
+        log_probs: &[f32],
+        alternatives: usize,
+    ) -> Self {
+        let mut top_logprobs: Vec<_> = log_probs
+            .iter()
+            .enumerate()
+            .map(|(id, &logprob)| (id as TokenId, logprob))
+            .collect();
+        let n = alternatives.min(top_logprobs.len());
+        top_logprobs.partial_sort(n, |a, b| b.1.total_cmp(&a.1));
+        top_logprobs.truncate(n);
+
+        Self {
+            token,
+            text_range,
+            logprob: log_probs[token as usize],
+            top_logprobs,
+        }
+    }
 }
 
 /// Feedback from a caller to [InferenceSession::infer], sent as the return
@@ -944,6 +1680,9 @@ pub fn feed_prompt_callback<'a, E: std::error::Error + Send + Sync + 'static>(
 
 /// An [InferenceResponse] callback that will halt inference when a `stop_sequence` is generated.
 /// This callback is used in [InferenceSession::infer] in chat_mode.
+///
+/// Consider using [InferenceRequest::stop_sequences] instead, which supports multiple stop
+/// sequences and finds them anywhere in the generated text.
 pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync + 'static>(
     stop_sequence: &'a str,
     mut callback: impl FnMut(String) + 'a,
@@ -1006,3 +1745,192 @@ fn kv_memory(
 
     (memory_k, memory_v)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock_model::MockModel,
+        samplers::{Bias, SampleGreedy, SampleRandom, SamplerChain},
+        TokenBias,
+    };
+
+    fn feed_incremental(session: &mut InferenceSession, model: &MockModel, prompt: &[TokenId]) {
+        session
+            .feed_prompt_incremental(model, prompt, &mut Default::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })
+            .unwrap();
+    }
+
+    fn feed_incremental_text(session: &mut InferenceSession, model: &MockModel, prompt: &str) {
+        session
+            .feed_prompt_incremental(model, prompt, &mut Default::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_feed_prompt_incremental_reuses_common_prefix() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+
+        feed_incremental(&mut session, &model, &[3, 4, 5, 6]);
+        feed_incremental(&mut session, &model, &[3, 4, 7]);
+        assert_eq!(session.tokens(), [3, 4, 7]);
+        assert_eq!(session.n_past, 3);
+        assert_eq!(MockModel::stored_token(&session, 2), Some(7));
+        assert_eq!(session.last_logits[8], 1.0);
+
+        // A prompt that ends earlier evaluates its last token again.
+        feed_incremental(&mut session, &model, &[3, 4]);
+        assert_eq!(session.tokens(), [3, 4]);
+        assert_eq!(session.last_logits[5], 1.0);
+    }
+
+    #[test]
+    fn test_fork_copies_independent_state() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+        feed_incremental(&mut session, &model, &[3, 4, 5]);
+
+        let mut fork = session.fork(&model).unwrap();
+        assert_eq!(fork.tokens(), session.tokens());
+        assert_eq!(fork.decoded_tokens(), b"abc");
+        assert_eq!(fork.n_past, 3);
+        assert_eq!(fork.last_logits, session.last_logits);
+        assert_eq!(fork.snapshot().memory_k, session.snapshot().memory_k);
+        assert_eq!(fork.snapshot().memory_v, session.snapshot().memory_v);
+
+        // Changing the fork leaves the original as it was.
+        feed_incremental(&mut fork, &model, &[3, 7, 8]);
+        assert_eq!(session.tokens(), [3, 4, 5]);
+        assert_eq!(MockModel::stored_token(&session, 1), Some(4));
+        assert_eq!(MockModel::stored_token(&fork, 1), Some(7));
+        assert_eq!(session.last_logits[6], 1.0);
+        assert_eq!(fork.last_logits[9], 1.0);
+    }
+
+    #[test]
+    fn test_feed_prompt_incremental_with_empty_control_tokens() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let mut session = model.start_session(Default::default());
+
+        let mut fed = vec![];
+        session
+            .feed_prompt_incremental(&model, "abc", &mut Default::default(), |bytes| {
+                fed.extend_from_slice(bytes);
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })
+            .unwrap();
+        assert_eq!(session.tokens(), [1, 3, 4, 5]);
+        assert_eq!(fed, b"abc");
+
+        feed_incremental_text(&mut session, &model, "abd");
+        assert_eq!(session.tokens(), [1, 3, 4, 6]);
+        assert_eq!(MockModel::stored_token(&session, 3), Some(6));
+
+        // The tokens of the model, such as the end of text, can be fed again.
+        session
+            .sync_tokens(&model, &[1, 3, 2, 4], &mut Default::default())
+            .unwrap();
+        assert_eq!(session.tokens(), [1, 3, 2, 4]);
+        assert_eq!(session.decoded_tokens(), b"ab");
+        assert_eq!(session.last_logits[5], 1.0);
+    }
+
+    #[test]
+    fn test_feed_prompt_incremental_with_different_prompt() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+
+        feed_incremental(&mut session, &model, &[3, 4, 5]);
+        feed_incremental(&mut session, &model, &[10, 11]);
+        assert_eq!(session.tokens(), [10, 11]);
+        assert_eq!(session.decoded_tokens(), b"hi");
+        assert_eq!(session.n_past, 2);
+        assert_eq!(MockModel::stored_token(&session, 0), Some(10));
+        assert_eq!(MockModel::stored_token(&session, 1), Some(11));
+        assert_eq!(session.last_logits[12], 1.0);
+    }
+
+    #[test]
+    fn test_infer_ends_when_every_token_is_banned() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+        // The end-of-text token is never banned, so it is biased away instead.
+        let sampler = SamplerChain::new(SampleRandom).with(Bias {
+            bias_tokens: TokenBias::new(vec![(model.eot_token_id(), f32::NEG_INFINITY)]),
+        });
+        let parameters = InferenceParameters {
+            sampler: Arc::new(sampler),
+        };
+        let banned_phrases: Vec<String> = std::iter::once('<')
+            .chain('a'..='z')
+            .map(String::from)
+            .collect();
+
+        let mut text = String::new();
+        session
+            .infer(
+                &model,
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    banned_phrases: &banned_phrases,
+                    ..InferenceRequest::new("a".into(), &parameters)
+                },
+                &mut Default::default(),
+                |response| {
+                    if let InferenceResponse::InferredToken(token) = response {
+                        text.push_str(&token);
+                    }
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        assert_eq!(text, "");
+        assert_eq!(session.tokens(), [1, 3, model.eot_token_id()]);
+    }
+
+    #[test]
+    fn test_infer_token_logprobs_locate_stop_sequence() {
+        let model = MockModel::new(32);
+        let mut session = model.start_session(Default::default());
+        let parameters = InferenceParameters {
+            sampler: Arc::new(SamplerChain::new(SampleGreedy)),
+        };
+        let stop_sequences = ["de".to_string()];
+
+        let mut text = String::new();
+        let mut logprobs = vec![];
+        session
+            .infer(
+                &model,
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    stop_sequences: &stop_sequences,
+                    token_logprobs: Some(1),
+                    ..InferenceRequest::new("a".into(), &parameters)
+                },
+                &mut Default::default(),
+                |response| {
+                    match response {
+                        InferenceResponse::InferredToken(token) => text.push_str(&token),
+                        InferenceResponse::TokenLogprobs(token) => logprobs.push(token),
+                        _ => {}
+                    }
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        assert_eq!(text, "bc");
+        let ranges: Vec<_> = logprobs
+            .iter()
+            .map(|l| (l.token, l.text_range.clone()))
+            .collect();
+        assert_eq!(ranges, [(4, 0..1), (5, 1..2), (6, 2..3), (7, 3..4)]);
+        let sent = logprobs.iter().filter(|l| l.text_range.end <= text.len());
+        assert_eq!(sent.map(|l| l.token).collect::<Vec<_>>(), [4, 5]);
+    }
+}
@@ -7,10 +7,18 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+mod beam_search;
+mod best_of;
+mod context_shift;
 mod inference_session;
 mod loader;
 mod lora;
+#[cfg(test)]
+mod mock_model;
+mod prefix_cache;
 mod quantize;
+mod scheduler;
+mod speculative;
 mod tokenizer;
 
 pub mod model;
@@ -22,11 +30,15 @@ use std::sync::Arc;
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+pub use beam_search::{BeamHypothesis, BeamSearchParameters};
+pub use best_of::Completion;
+pub use context_shift::{ContextShift, ContextShiftError, KvMemoryLayout, Rope};
 pub use inference_session::{
-    conversation_inference_callback, feed_prompt_callback, GraphOutputs, InferenceError,
-    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    ModelKVMemoryType, RewindError, SnapshotError,
+    conversation_inference_callback, feed_prompt_callback, BatchSequence, BuildContext,
+    GraphOutputs, InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse,
+    InferenceSession, InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef,
+    InferenceStats, JsonInferenceError, ModelKVMemoryType, RewindError, SequenceMemory,
+    SnapshotError, TokenLogprobs,
 };
 pub use loader::{
     load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, FormatMagic,
@@ -35,9 +47,12 @@ pub use loader::{
 pub use lora::{LoraAdapter, LoraParameters};
 pub use memmap2::Mmap;
 pub use model::{Hyperparameters, KnownModel, Model, ModelParameters, OutputRequest};
+pub use prefix_cache::PrefixCache;
 pub use quantize::{quantize, QuantizeError, QuantizeProgress};
 pub use regex::Regex;
 pub use samplers::Sampler;
+pub use scheduler::{ScheduledGeneration, ScheduledRequest, Scheduler, SchedulerConfig};
+pub use speculative::{DraftModel, Drafter, PromptLookup};
 pub use tokenizer::{
     InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer, TokenizerLoadError,
     TokenizerSource,
@@ -59,6 +74,8 @@ pub struct InferenceParameters {
     ///
     /// A recommended default sampler is [TopPTopK](samplers::TopPTopK), which is a standard
     /// sampler that offers a [Default](samplers::TopPTopK::default) implementation.
+    /// Custom samplers can be composed out of individual stages with a
+    /// [SamplerChain](samplers::SamplerChain).
     pub sampler: Arc<dyn Sampler>,
 }
 
@@ -0,0 +1,180 @@
+//! A small model for testing the code that drives inference, without loading weights.
+
+use std::sync::Mutex;
+
+use crate::{
+    tokenizer::EmbeddedTokenizer, BatchSequence, InferenceSession, InferenceSessionConfig,
+    KvMemoryLayout, Model, ModelParameters, OutputRequest, TokenId, Tokenizer,
+};
+
+/// The number of layers of the memory of a [MockModel].
+pub(crate) const N_LAYER: usize = 2;
+/// The number of elements of each token in each layer of the memory of a [MockModel].
+pub(crate) const N_EMBD: usize = 4;
+/// The end of text token of a [MockModel].
+pub(crate) const EOT: TokenId = 2;
+
+/// A model whose vocabulary is `<unk>`, `<s>`, `</s>` and the lowercase letters, which
+/// always predicts the token that follows the last one it was fed, or the end of text after
+/// `z`, and stores each token that it is fed in every byte of its memory for that token.
+pub(crate) struct MockModel {
+    tokenizer: Tokenizer,
+    params: ModelParameters,
+    bot_token: Option<TokenId>,
+    /// The number of sequences of each call to [Model::evaluate_batch].
+    pub(crate) batch_sizes: Mutex<Vec<usize>>,
+}
+impl MockModel {
+    pub(crate) fn new(context_size: usize) -> Self {
+        Self::with_control_tokens(context_size, ["<unk>", "<s>", "</s>"], None)
+    }
+
+    /// Creates a model whose control tokens have no text, as with the vocabularies of
+    /// Hugging Face tokenizers and those converted by llama.cpp, and which starts each
+    /// prompt with `<s>`.
+    pub(crate) fn with_empty_control_tokens(context_size: usize) -> Self {
+        Self::with_control_tokens(context_size, ["", "", ""], Some(1))
+    }
+
+    fn with_control_tokens(
+        context_size: usize,
+        control_tokens: [&str; 3],
+        bot_token: Option<TokenId>,
+    ) -> Self {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        let tokens = control_tokens
+            .into_iter()
+            .map(|token| token.as_bytes().to_vec())
+            .chain((b'a'..=b'z').map(|letter| vec![letter]));
+        for (id, token) in tokens.enumerate() {
+            tokenizer.push_token(id as TokenId, token, 0.0);
+        }
+
+        Self {
+            tokenizer: tokenizer.into(),
+            params: ModelParameters {
+                context_size,
+                ..Default::default()
+            },
+            bot_token,
+            batch_sizes: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns the token that was stored in the memory of `session` at `position`, if
+    /// all of its bytes agree.
+    pub(crate) fn stored_token(session: &InferenceSession, position: usize) -> Option<TokenId> {
+        let layout = memory_layout();
+        let context_size = session.memory_k.nelements() / (N_LAYER * N_EMBD);
+        let mut bytes = vec![];
+        for memory in [&session.memory_k, &session.memory_v] {
+            let token_size = layout.token_width * memory.element_size();
+            for il in 0..layout.n_layer {
+                let mut token = vec![0; token_size];
+                let offset = (il * context_size + position) * token_size;
+                // SAFETY: The memory is on the CPU, and is not being written to.
+                unsafe { memory.read_data(offset, &mut token) };
+                bytes.extend(token);
+            }
+        }
+        let first = bytes[0];
+        bytes
+            .iter()
+            .all(|&byte| byte == first)
+            .then_some(first as TokenId)
+    }
+}
+impl MockModel {
+    /// The logits that are predicted after `token`.
+    fn logits_after(&self, token: TokenId) -> Vec<f32> {
+        let mut logits = vec![0.0; self.tokenizer.len()];
+        let next = if token as usize + 1 < self.tokenizer.len() {
+            token as usize + 1
+        } else {
+            EOT as usize
+        };
+        logits[next] = 1.0;
+        logits
+    }
+}
+impl Model for MockModel {
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        InferenceSession::new(config, &self.params, N_LAYER, N_EMBD, self.tokenizer.len())
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        input_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) {
+        let context_size = self.params.context_size;
+        for (index, &token) in input_tokens.iter().enumerate() {
+            let position = session.n_past + index;
+            for memory in [&mut session.memory_k, &mut session.memory_v] {
+                let token_size = N_EMBD * memory.element_size();
+                // SAFETY: The memory is on the CPU, and we have exclusive access to it.
+                let memory = unsafe {
+                    std::slice::from_raw_parts_mut(memory.data() as *mut u8, memory.nbytes())
+                };
+                for il in 0..N_LAYER {
+                    let offset = (il * context_size + position) * token_size;
+                    memory[offset..offset + token_size].fill(token as u8);
+                }
+            }
+        }
+        session.n_past += input_tokens.len();
+
+        if let Some(all_logits) = &mut output_request.all_logits {
+            all_logits.clear();
+            for &token in input_tokens {
+                all_logits.extend(self.logits_after(token));
+            }
+        }
+        session.last_logits = self.logits_after(*input_tokens.last().unwrap());
+    }
+
+    fn evaluate_batch(&self, batch: &mut [BatchSequence]) {
+        self.batch_sizes.lock().unwrap().push(batch.len());
+        for sequence in batch {
+            self.evaluate(
+                sequence.session,
+                sequence.input_tokens,
+                sequence.output_request,
+            );
+        }
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn context_size(&self) -> usize {
+        self.params.context_size
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        self.bot_token
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        EOT
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        Some(memory_layout())
+    }
+}
+
+fn memory_layout() -> KvMemoryLayout {
+    KvMemoryLayout {
+        n_layer: N_LAYER,
+        token_width: N_EMBD,
+        transposed_values: false,
+        rope: None,
+    }
+}
@@ -1,6 +1,6 @@
-use ggml::Tensor;
+use ggml::{accelerator::Backend, Tensor};
 
-use crate::{InferenceSession, OutputRequest};
+use crate::{BatchSequence, GraphOutputs, InferenceSession, OutputRequest};
 
 /// Return result for just the last token
 pub fn read_last_token(
@@ -57,3 +57,165 @@ pub fn extract_embeddings(
         embeddings.copy_from_slice(&all_embeddings[n_embd * (n - 1)..]);
     }
 }
+
+/// The maximum number of sequences that [InferenceSession::compute_batch] can evaluate in
+/// one graph of a model with `n_layer` layers, each of which adds `nodes_per_layer` nodes to
+/// the graph, and `nodes_per_sequence` more for each sequence.
+///
+/// A graph holds at most [ggml::MAX_NODES] nodes, and building a larger one aborts the
+/// process, so batches with more sequences must be evaluated in several graphs. This is at
+/// least one, as a single sequence is evaluated like [InferenceSession::compute] does.
+pub fn max_batch_sequences(
+    n_layer: usize,
+    nodes_per_layer: usize,
+    nodes_per_sequence: usize,
+) -> usize {
+    // Leave room for the nodes before the first layer and after the last one.
+    let layer_nodes = ggml::MAX_NODES.saturating_sub(16) / n_layer.max(1);
+    (layer_nodes.saturating_sub(nodes_per_layer) / nodes_per_sequence.max(1)).max(1)
+}
+
+/// Evaluates the `batch` for [Model::evaluate_batch](crate::Model::evaluate_batch) in as few
+/// graphs as possible, by calling `evaluate_graph` with each part of it that fits in one
+/// graph, and `evaluate` with each sequence that can't be part of a graph with others.
+///
+/// Each part has at most `max_sequences` sequences, as found with [max_batch_sequences], and
+/// at most [InferenceSessionConfig::n_batch](crate::InferenceSessionConfig::n_batch) tokens
+/// of the session whose buffers hold the graph, which is the first one. As the memory that a
+/// graph needs grows with its number of tokens, a part then needs no more than that session
+/// evaluating `n_batch` tokens on its own. A sequence with more tokens is a part of its own.
+///
+/// [InferenceSession::compute_batch] computes on the CPU, so sessions whose memory is not on
+/// the CPU, or that compute with Metal, are evaluated one at a time.
+pub fn evaluate_in_parts(
+    batch: &mut [BatchSequence],
+    max_sequences: usize,
+    mut evaluate: impl FnMut(&mut BatchSequence),
+    mut evaluate_graph: impl FnMut(&mut [BatchSequence]),
+) {
+    let mut rest = batch;
+    while !rest.is_empty() {
+        if !can_compute_batch(rest[0].session) {
+            let (sequence, tail) = std::mem::take(&mut rest).split_first_mut().unwrap();
+            evaluate(sequence);
+            rest = tail;
+            continue;
+        }
+
+        let n_batch = rest[0].session.config.n_batch;
+        let mut len = 1;
+        let mut tokens = rest[0].input_tokens.len();
+        while let Some(sequence) = rest.get(len) {
+            tokens += sequence.input_tokens.len();
+            if len == max_sequences || tokens > n_batch || !can_compute_batch(sequence.session) {
+                break;
+            }
+            len += 1;
+        }
+        let (part, tail) = std::mem::take(&mut rest).split_at_mut(len);
+        evaluate_graph(part);
+        rest = tail;
+    }
+}
+
+/// Returns whether [InferenceSession::compute_batch] can evaluate the `session`.
+fn can_compute_batch(session: &InferenceSession) -> bool {
+    #[cfg(feature = "metal")]
+    if session.metal_context.is_some() {
+        return false;
+    }
+    session.memory_k.backend() == Backend::Cpu && session.memory_v.backend() == Backend::Cpu
+}
+
+/// Read the results of [InferenceSession::compute_batch] into each sequence of the `batch`,
+/// like [read_last_token], [extract_logits] and [extract_embeddings] do for one session
+pub fn read_batch_outputs(
+    batch: &mut [BatchSequence],
+    outputs: &GraphOutputs,
+    n_vocab: usize,
+    n_embd: usize,
+) {
+    let f32_size = std::mem::size_of::<f32>();
+    let mut input_offset = 0;
+    for sequence in batch {
+        let n = sequence.input_tokens.len();
+        let last = input_offset + n - 1;
+
+        assert_eq!(sequence.session.last_logits.len(), n_vocab);
+        // SAFETY: Same rationale as for the "Extract logits" section applies, and each
+        // sequence reads its own rows of the outputs.
+        unsafe {
+            outputs.result.read_data(
+                n_vocab * last * f32_size,
+                bytemuck::cast_slice_mut(&mut sequence.session.last_logits),
+            );
+            if let Some(all_logits) = &mut sequence.output_request.all_logits {
+                all_logits.resize(n_vocab * n, 0.0);
+                outputs.result.read_data(
+                    n_vocab * input_offset * f32_size,
+                    bytemuck::cast_slice_mut(all_logits),
+                );
+            }
+            if let Some(embeddings) = &mut sequence.output_request.embeddings {
+                embeddings.resize(n_embd, 0.0);
+                outputs.embedding_result.read_data(
+                    n_embd * last * f32_size,
+                    bytemuck::cast_slice_mut(embeddings),
+                );
+            }
+        }
+
+        input_offset += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_model::MockModel, InferenceSessionConfig, Model, TokenId};
+
+    #[test]
+    fn test_max_batch_sequences_fits_in_graph() {
+        // A 7B LLaMA has 32 layers, and the largest has 80.
+        assert_eq!(max_batch_sequences(32, 20, 24), 4);
+        assert_eq!(max_batch_sequences(80, 20, 24), 1);
+        assert_eq!(max_batch_sequences(2, 20, 24), 84);
+    }
+
+    #[test]
+    fn test_evaluate_in_parts_limits_tokens() {
+        let model = MockModel::new(32);
+        let config = InferenceSessionConfig {
+            n_batch: 4,
+            ..Default::default()
+        };
+        let mut sessions: Vec<_> = (0..6).map(|_| model.start_session(config)).collect();
+        let tokens: [&[TokenId]; 6] = [&[3, 4], &[5, 6], &[7], &[8, 9, 10], &[11], &[3; 5]];
+        let mut output_requests: Vec<_> = (0..6).map(|_| OutputRequest::default()).collect();
+        let mut batch: Vec<_> = sessions
+            .iter_mut()
+            .zip(tokens)
+            .zip(&mut output_requests)
+            .map(|((session, input_tokens), output_request)| BatchSequence {
+                session,
+                input_tokens,
+                output_request,
+            })
+            .collect();
+
+        let mut parts = vec![];
+        evaluate_in_parts(
+            &mut batch,
+            2,
+            |_| unreachable!("the sessions are on the CPU"),
+            |part| {
+                parts.push(
+                    part.iter()
+                        .map(|s| s.input_tokens.len())
+                        .collect::<Vec<_>>(),
+                )
+            },
+        );
+        assert_eq!(parts, [vec![2, 2], vec![1, 3], vec![1], vec![5]]);
+    }
+}
@@ -12,8 +12,8 @@ use regex::Regex;
 use thiserror::Error;
 
 use crate::{
-    loader::TensorLoader, tokenizer::TokenId, FileType, InferenceSession, InferenceSessionConfig,
-    LoadError, LoadProgress, Tokenizer, TokenizerSource,
+    loader::TensorLoader, tokenizer::TokenId, BatchSequence, FileType, InferenceSession,
+    InferenceSessionConfig, KvMemoryLayout, LoadError, LoadProgress, Tokenizer, TokenizerSource,
 };
 
 /// Common functions for model evaluation
@@ -65,6 +65,24 @@ pub trait KnownModel: Send + Sync {
         output_request: &mut OutputRequest,
     );
 
+    /// Evaluates the input tokens of several independent sessions of this model, as if
+    /// [Self::evaluate] was called for each of them.
+    ///
+    /// By default, the sequences are evaluated one after the other. Models that can
+    /// evaluate all of them in one graph with [InferenceSession::compute_batch] should
+    /// override this, as that is much faster when there are many sequences. Such models
+    /// must split the batch into parts that fit in one graph, and evaluate the sessions
+    /// that are not on the CPU one at a time, with [common::evaluate_in_parts].
+    fn evaluate_batch(&self, batch: &mut [BatchSequence]) {
+        for sequence in batch {
+            self.evaluate(
+                sequence.session,
+                sequence.input_tokens,
+                sequence.output_request,
+            );
+        }
+    }
+
     /// Get the hyperparameters for this model.
     fn hyperparameters(&self) -> &Self::Hyperparameters;
 
@@ -92,6 +110,12 @@ pub trait KnownModel: Send + Sync {
         // Assume we can't delete unless otherwise specified
         false
     }
+
+    /// Returns how this model stores tokens in the memory of its sessions, if their
+    /// context can be [shifted](InferenceSession::shift_context).
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        None
+    }
 }
 
 /// A type-erased model to allow for interacting with a model without knowing
@@ -111,6 +135,16 @@ pub trait Model: Send + Sync {
         output_request: &mut OutputRequest,
     );
 
+    /// Evaluates the input tokens of several independent sessions of this model, as if
+    /// [Self::evaluate] was called for each of them. Each session must have room for its
+    /// tokens in its context window.
+    ///
+    /// Only LLaMA models build one graph for several sequences, which is faster than
+    /// evaluating them one at a time. The other architectures (GPT-J, GPT-NeoX, Falcon,
+    /// BLOOM, MPT and GPT-2) evaluate the sequences one after the other, and sessions that
+    /// use a GPU are always evaluated one at a time.
+    fn evaluate_batch(&self, batch: &mut [BatchSequence]);
+
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
@@ -126,6 +160,10 @@ pub trait Model: Send + Sync {
 
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool;
+
+    /// Returns how this model stores tokens in the memory of its sessions, if their
+    /// context can be [shifted](InferenceSession::shift_context).
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout>;
 }
 impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
@@ -141,6 +179,10 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         KnownModel::evaluate(self, session, input_tokens, output_request)
     }
 
+    fn evaluate_batch(&self, batch: &mut [BatchSequence]) {
+        KnownModel::evaluate_batch(self, batch)
+    }
+
     fn tokenizer(&self) -> &Tokenizer {
         KnownModel::tokenizer(self)
     }
@@ -160,6 +202,10 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn supports_rewind(&self) -> bool {
         KnownModel::supports_rewind(self)
     }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        KnownModel::kv_memory_layout(self)
+    }
 }
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
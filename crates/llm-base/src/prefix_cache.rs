@@ -0,0 +1,472 @@
+use ggml::accelerator::Backend;
+use tracing::log;
+
+use crate::{
+    InferenceError, InferenceFeedback, InferenceSession, KvMemoryLayout, Model, OutputRequest,
+    Prompt, TokenId,
+};
+
+/// A cache of the memory of prompts that were fed to sessions of a model, so that a new
+/// session whose prompt starts with the same tokens, such as a shared system prompt,
+/// can copy their keys and values instead of evaluating them again.
+///
+/// The cache holds at most `capacity` bytes, and evicts the least recently used prompts
+/// to make room for new ones. A cached prompt can be reused by any prompt that starts with
+/// some of its tokens, so a prompt that extends a cached one replaces it.
+///
+/// Only the tokens of each prompt are stored, rather than the whole memory of the session,
+/// which requires a model that describes its [memory layout](Model::kv_memory_layout). The
+/// cache must only be used with sessions of one model. To share it between threads, wrap it
+/// in a [Mutex](std::sync::Mutex).
+#[derive(Debug)]
+pub struct PrefixCache {
+    capacity: usize,
+    // The cached prompts, from the least to the most recently used.
+    entries: Vec<PrefixCacheEntry>,
+}
+impl PrefixCache {
+    /// Creates an empty cache that holds at most `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: vec![],
+        }
+    }
+
+    /// The number of bytes used by the cached prompts.
+    pub fn memory_usage(&self) -> usize {
+        self.entries.iter().map(PrefixCacheEntry::size).sum()
+    }
+
+    /// The number of cached prompts.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no prompts are cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all of the cached prompts.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Feeds `prompt` to `session` like [InferenceSession::feed_prompt], but copies the
+    /// memory of the longest cached prefix of it first, and caches the memory of the whole
+    /// prompt afterwards.
+    ///
+    /// The cache is only used when the session is empty; otherwise, this is the same as
+    /// [InferenceSession::feed_prompt], and nothing is cached. The `output_request` and the
+    /// `callback` only see the tokens that are evaluated, which don't include the copied ones.
+    pub fn feed_prompt<'a, E: std::error::Error + Send + Sync + 'static, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        session: &mut InferenceSession,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        let layout = self.layout_for(model, session);
+        let Some(layout) = layout.filter(|_| session.n_past == 0) else {
+            return session.feed_prompt(model, prompt, output_request, callback);
+        };
+
+        let tokens = prompt.into().to_tokens(model.tokenizer(), true)?;
+        let restored = self.restore(model, session, layout, &tokens);
+        log::trace!(
+            "Restored {restored} of {} prompt tokens from the prefix cache",
+            tokens.len()
+        );
+        if restored < tokens.len() {
+            session.feed_tokens(model, &tokens[restored..], output_request, callback)?;
+        }
+
+        self.insert(model, session, layout);
+        Ok(())
+    }
+
+    /// Returns the memory layout of `model` if the memory of the `session` can be copied.
+    fn layout_for(&self, model: &dyn Model, session: &InferenceSession) -> Option<KvMemoryLayout> {
+        let on_cpu = session.memory_k.backend() == Backend::Cpu
+            && session.memory_v.backend() == Backend::Cpu;
+        on_cpu.then(|| model.kv_memory_layout()).flatten()
+    }
+
+    /// Copies the memory of the longest cached prefix of `tokens` into the empty `session`,
+    /// and returns the number of tokens that were copied.
+    fn restore(
+        &mut self,
+        model: &dyn Model,
+        session: &mut InferenceSession,
+        layout: KvMemoryLayout,
+        tokens: &[TokenId],
+    ) -> usize {
+        let memory_types = (session.memory_k.get_type(), session.memory_v.get_type());
+        let best = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.layout == layout && entry.memory_types == memory_types)
+            .map(|(index, entry)| (index, common_prefix_len(&entry.tokens, tokens)))
+            .max_by_key(|&(_, common)| common);
+        let Some((index, common)) = best.filter(|&(_, common)| common > 0) else {
+            return 0;
+        };
+
+        // Move the entry to the end, as the most recently used.
+        let entry = self.entries.remove(index);
+        // The logits are only known for the last token of the entry, so if the prompt
+        // ends earlier, its last token is evaluated again.
+        let restored = if common == entry.tokens.len() || common < tokens.len() {
+            common
+        } else {
+            common - 1
+        };
+
+        if restored > 0 {
+            let context_size = model.context_size();
+            // SAFETY: The memory is on the CPU, and we have exclusive access to the session.
+            unsafe {
+                let memory_k = std::slice::from_raw_parts_mut(
+                    session.memory_k.data() as *mut u8,
+                    session.memory_k.nbytes(),
+                );
+                copy_tokens(
+                    &layout,
+                    context_size,
+                    entry.tokens.len(),
+                    restored,
+                    false,
+                    |dst, src, len| {
+                        let element_size = ggml::type_size(entry.memory_types.0);
+                        memory_k[dst * element_size..(dst + len) * element_size].copy_from_slice(
+                            &entry.memory_k[src * element_size..(src + len) * element_size],
+                        );
+                    },
+                );
+
+                let memory_v = std::slice::from_raw_parts_mut(
+                    session.memory_v.data() as *mut u8,
+                    session.memory_v.nbytes(),
+                );
+                copy_tokens(
+                    &layout,
+                    context_size,
+                    entry.tokens.len(),
+                    restored,
+                    layout.transposed_values,
+                    |dst, src, len| {
+                        let element_size = ggml::type_size(entry.memory_types.1);
+                        memory_v[dst * element_size..(dst + len) * element_size].copy_from_slice(
+                            &entry.memory_v[src * element_size..(src + len) * element_size],
+                        );
+                    },
+                );
+            }
+
+            session.n_past = restored;
+            session.tokens = entry.tokens[..restored].to_vec();
+            session.redecode_tokens(model);
+            if restored == entry.tokens.len() {
+                session.last_logits.clone_from(&entry.last_logits);
+            }
+        }
+
+        self.entries.push(entry);
+        restored
+    }
+
+    /// Caches the memory of all of the tokens of `session`.
+    fn insert(&mut self, model: &dyn Model, session: &InferenceSession, layout: KvMemoryLayout) {
+        let tokens = &session.tokens[..session.n_past];
+        let memory_types = (session.memory_k.get_type(), session.memory_v.get_type());
+        let is_same_kind =
+            |entry: &PrefixCacheEntry| entry.layout == layout && entry.memory_types == memory_types;
+
+        // A cached prompt that starts with these tokens already covers them.
+        if let Some(index) = self
+            .entries
+            .iter()
+            .position(|entry| is_same_kind(entry) && entry.tokens.starts_with(tokens))
+        {
+            let entry = self.entries.remove(index);
+            self.entries.push(entry);
+            return;
+        }
+
+        let context_size = model.context_size();
+        let n = tokens.len();
+        let size = PrefixCacheEntry::size_for(&layout, memory_types, n, session.last_logits.len());
+        if n == 0 || size > self.capacity {
+            return;
+        }
+
+        let mut entry = PrefixCacheEntry {
+            tokens: tokens.to_vec(),
+            last_logits: session.last_logits.clone(),
+            layout,
+            memory_types,
+            memory_k: vec![0; PrefixCacheEntry::memory_len(&layout, memory_types.0, n)],
+            memory_v: vec![0; PrefixCacheEntry::memory_len(&layout, memory_types.1, n)],
+        };
+
+        // SAFETY: The memory is on the CPU, and is not being written to.
+        copy_tokens(&layout, context_size, n, n, false, |src, dst, len| {
+            let element_size = ggml::type_size(memory_types.0);
+            unsafe {
+                session.memory_k.read_data(
+                    src * element_size,
+                    &mut entry.memory_k[dst * element_size..(dst + len) * element_size],
+                )
+            };
+        });
+        copy_tokens(
+            &layout,
+            context_size,
+            n,
+            n,
+            layout.transposed_values,
+            |src, dst, len| {
+                let element_size = ggml::type_size(memory_types.1);
+                unsafe {
+                    session.memory_v.read_data(
+                        src * element_size,
+                        &mut entry.memory_v[dst * element_size..(dst + len) * element_size],
+                    )
+                };
+            },
+        );
+
+        // This replaces the cached prompts that it starts with.
+        self.entries
+            .retain(|cached| !(is_same_kind(cached) && tokens.starts_with(&cached.tokens)));
+        while self.memory_usage() + entry.size() > self.capacity {
+            self.entries.remove(0);
+        }
+        self.entries.push(entry);
+    }
+}
+
+/// The memory of a cached prompt, with the elements of its tokens in the order of
+/// [copy_tokens].
+#[derive(Debug)]
+struct PrefixCacheEntry {
+    tokens: Vec<TokenId>,
+    last_logits: Vec<f32>,
+    layout: KvMemoryLayout,
+    memory_types: (ggml::Type, ggml::Type),
+    memory_k: Vec<u8>,
+    memory_v: Vec<u8>,
+}
+impl PrefixCacheEntry {
+    fn size(&self) -> usize {
+        Self::size_for(
+            &self.layout,
+            self.memory_types,
+            self.tokens.len(),
+            self.last_logits.len(),
+        )
+    }
+
+    /// The size of an entry of `n_tokens` tokens and `n_logits` logits.
+    fn size_for(
+        layout: &KvMemoryLayout,
+        memory_types: (ggml::Type, ggml::Type),
+        n_tokens: usize,
+        n_logits: usize,
+    ) -> usize {
+        Self::memory_len(layout, memory_types.0, n_tokens)
+            + Self::memory_len(layout, memory_types.1, n_tokens)
+            + n_tokens * std::mem::size_of::<TokenId>()
+            + n_logits * std::mem::size_of::<f32>()
+    }
+
+    /// The number of bytes of the keys or the values of `n_tokens` tokens.
+    fn memory_len(layout: &KvMemoryLayout, memory_type: ggml::Type, n_tokens: usize) -> usize {
+        n_tokens * layout.n_layer * layout.token_width * ggml::type_size(memory_type)
+    }
+}
+
+fn common_prefix_len(a: &[TokenId], b: &[TokenId]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Calls `copy` with the offset in the memory of a session, the offset in the memory of a
+/// cache entry of `n_cached` tokens, and the length, in elements, of each run of elements
+/// of the first `n_tokens` of them.
+fn copy_tokens(
+    layout: &KvMemoryLayout,
+    context_size: usize,
+    n_cached: usize,
+    n_tokens: usize,
+    transposed: bool,
+    mut copy: impl FnMut(usize, usize, usize),
+) {
+    for il in 0..layout.n_layer {
+        let layer = il * context_size * layout.token_width;
+        let cached = il * n_cached * layout.token_width;
+        if transposed {
+            for d in 0..layout.token_width {
+                copy(layer + d * context_size, cached + d * n_cached, n_tokens);
+            }
+        } else {
+            copy(layer, cached, n_tokens * layout.token_width);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_model::MockModel;
+
+    /// Feeds `prompt` to a new session through the `cache`, and returns the session and
+    /// the text of the tokens that were evaluated.
+    fn feed(
+        cache: &mut PrefixCache,
+        model: &MockModel,
+        prompt: &[TokenId],
+    ) -> (InferenceSession, Vec<u8>) {
+        let mut session = model.start_session(Default::default());
+        let mut evaluated = vec![];
+        cache
+            .feed_prompt(
+                model,
+                &mut session,
+                prompt,
+                &mut Default::default(),
+                |bytes| {
+                    evaluated.extend_from_slice(bytes);
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        (session, evaluated)
+    }
+
+    fn cached_tokens(cache: &PrefixCache) -> Vec<Vec<TokenId>> {
+        cache.entries.iter().map(|e| e.tokens.clone()).collect()
+    }
+
+    #[test]
+    fn test_restores_and_replaces_extended_prefix() {
+        let model = MockModel::new(32);
+        let mut cache = PrefixCache::new(usize::MAX);
+
+        let (_, evaluated) = feed(&mut cache, &model, &[3, 4, 5]);
+        assert_eq!(evaluated, b"abc");
+
+        let (session, evaluated) = feed(&mut cache, &model, &[3, 4, 5, 6]);
+        assert_eq!(evaluated, b"d");
+        assert_eq!(session.tokens(), [3, 4, 5, 6]);
+        assert_eq!(session.decoded_tokens(), b"abcd");
+        for position in 0..4 {
+            assert_eq!(
+                MockModel::stored_token(&session, position),
+                Some(3 + position as TokenId)
+            );
+        }
+        assert_eq!(cached_tokens(&cache), [vec![3, 4, 5, 6]]);
+
+        // All of a cached prompt is restored, along with its logits.
+        let (session, evaluated) = feed(&mut cache, &model, &[3, 4, 5, 6]);
+        assert_eq!(evaluated, b"");
+        assert_eq!(session.last_logits[7], 1.0);
+    }
+
+    #[test]
+    fn test_feeds_text_with_empty_control_tokens() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let mut cache = PrefixCache::new(usize::MAX);
+        let mut session = model.start_session(Default::default());
+        let feed = |cache: &mut PrefixCache, session: &mut InferenceSession, prompt: &str| {
+            cache
+                .feed_prompt(&model, session, prompt, &mut Default::default(), |_| {
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                })
+                .unwrap();
+        };
+
+        feed(&mut cache, &mut session, "ab");
+        assert_eq!(session.tokens(), [1, 3, 4]);
+        assert_eq!(cached_tokens(&cache), [vec![1, 3, 4]]);
+
+        // A session that is not empty is fed without the cache.
+        feed(&mut cache, &mut session, "c");
+        assert_eq!(session.tokens(), [1, 3, 4, 5]);
+        assert_eq!(cached_tokens(&cache), [vec![1, 3, 4]]);
+
+        let mut session = model.start_session(Default::default());
+        feed(&mut cache, &mut session, "abd");
+        assert_eq!(session.tokens(), [1, 3, 4, 6]);
+        assert_eq!(MockModel::stored_token(&session, 0), Some(1));
+        assert_eq!(cached_tokens(&cache), [vec![1, 3, 4, 6]]);
+    }
+
+    #[test]
+    fn test_evaluates_last_token_of_shorter_prompt() {
+        let model = MockModel::new(32);
+        let mut cache = PrefixCache::new(usize::MAX);
+        feed(&mut cache, &model, &[3, 4, 5]);
+
+        // The logits after `b` are not cached, so it is evaluated again.
+        let (session, evaluated) = feed(&mut cache, &model, &[3, 4]);
+        assert_eq!(evaluated, b"b");
+        assert_eq!(session.tokens(), [3, 4]);
+        assert_eq!(session.n_past, 2);
+        assert_eq!(session.last_logits[5], 1.0);
+        assert_eq!(cached_tokens(&cache), [vec![3, 4, 5]]);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let model = MockModel::new(32);
+        let session = model.start_session(Default::default());
+        let memory_types = (session.memory_k.get_type(), session.memory_v.get_type());
+        let layout = model.kv_memory_layout().unwrap();
+        let entry_size =
+            PrefixCacheEntry::size_for(&layout, memory_types, 1, session.last_logits.len());
+        let mut cache = PrefixCache::new(2 * entry_size);
+
+        feed(&mut cache, &model, &[3]);
+        feed(&mut cache, &model, &[10]);
+        assert_eq!(cached_tokens(&cache), [[3], [10]]);
+
+        // Using a prompt makes it the most recently used.
+        let (_, evaluated) = feed(&mut cache, &model, &[3]);
+        assert_eq!(evaluated, b"");
+        assert_eq!(cached_tokens(&cache), [[10], [3]]);
+
+        feed(&mut cache, &model, &[12]);
+        assert_eq!(cached_tokens(&cache), [[3], [12]]);
+        assert_eq!(cache.memory_usage(), 2 * entry_size);
+
+        // A prompt that does not fit is not cached.
+        feed(&mut cache, &model, &[5, 6, 7, 8, 9, 10]);
+        assert_eq!(cached_tokens(&cache), [[3], [12]]);
+    }
+
+    #[test]
+    fn test_copy_tokens_runs() {
+        let layout = KvMemoryLayout {
+            n_layer: 2,
+            token_width: 2,
+            transposed_values: true,
+            rope: None,
+        };
+
+        let mut runs = vec![];
+        copy_tokens(&layout, 8, 3, 2, false, |session, cached, len| {
+            runs.push((session, cached, len))
+        });
+        assert_eq!(runs, [(0, 0, 4), (16, 6, 4)]);
+
+        runs.clear();
+        copy_tokens(&layout, 8, 3, 2, true, |session, cached, len| {
+            runs.push((session, cached, len))
+        });
+        assert_eq!(runs, [(0, 0, 2), (8, 3, 2), (16, 6, 2), (24, 9, 2)]);
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::Debug;
+
+use partial_sort::PartialSort;
+
+use super::{Sampler, SamplerState};
+use crate::{util, TokenId};
+
+/// A token that is being considered during sampling, along with its logit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    /// The ID of the token.
+    pub id: TokenId,
+    /// The (possibly modified) logit of the token.
+    pub logit: f32,
+}
+
+/// The set of tokens that are being considered during sampling.
+///
+/// This starts out as every token in the vocabulary with the logits produced by the model,
+/// and is then modified by each [SamplerStage] of a [SamplerChain] before a [TokenSelector]
+/// picks the final token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Candidates {
+    candidates: Vec<Candidate>,
+    sorted: bool,
+}
+impl Candidates {
+    /// Creates the candidates from the logits of an evaluation, where the index of each
+    /// logit is its token ID.
+    pub fn from_logits(logits: &[f32]) -> Self {
+        Self {
+            candidates: logits
+                .iter()
+                .enumerate()
+                .map(|(id, &logit)| Candidate {
+                    id: id as TokenId,
+                    logit,
+                })
+                .collect(),
+            sorted: false,
+        }
+    }
+
+    /// The number of candidates remaining.
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Whether there are no candidates remaining.
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Whether the candidates are known to be sorted by descending logit.
+    pub fn is_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// The candidates remaining, in their current order.
+    pub fn as_slice(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    /// Mutable access to the candidates remaining.
+    ///
+    /// As the logits may be changed, the candidates are no longer considered sorted.
+    pub fn as_mut_slice(&mut self) -> &mut [Candidate] {
+        self.sorted = false;
+        &mut self.candidates
+    }
+
+    /// Iterates over the candidates remaining, in their current order.
+    pub fn iter(&self) -> std::slice::Iter<'_, Candidate> {
+        self.candidates.iter()
+    }
+
+    /// Sorts the candidates by descending logit, if they are not already sorted.
+    pub fn sort(&mut self) {
+        if !self.sorted {
+            self.candidates.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+            self.sorted = true;
+        }
+    }
+
+    /// Sorts the `k` candidates with the highest logits to the front, in descending order,
+    /// and removes the rest.
+    pub fn keep_top(&mut self, k: usize) {
+        let k = k.min(self.candidates.len());
+        if !self.sorted {
+            self.candidates
+                .partial_sort(k, |a, b| b.logit.total_cmp(&a.logit));
+        }
+        self.candidates.truncate(k);
+        self.sorted = true;
+    }
+
+    /// Shortens the candidates to the first `len` entries, in their current order.
+    pub fn truncate(&mut self, len: usize) {
+        self.candidates.truncate(len);
+    }
+
+    /// Retains only the candidates for which `f` returns `true`.
+    pub fn retain(&mut self, f: impl FnMut(&Candidate) -> bool) {
+        self.candidates.retain(f);
+    }
+
+    /// Computes the probability of each candidate by applying softmax to their logits.
+    ///
+    /// The probabilities are in the same order as the candidates.
+    pub fn probabilities(&self) -> Vec<f32> {
+        let max_logit = self
+            .candidates
+            .iter()
+            .map(|c| c.logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        let mut probs: Vec<f32> = self
+            .candidates
+            .iter()
+            .map(|c| (c.logit - max_logit).exp())
+            .collect();
+        let sum: f32 = probs.iter().sum();
+        for p in probs.iter_mut() {
+            *p /= sum;
+        }
+        probs
+    }
+
+    /// Computes the log-probability of each candidate, as with [util::log_softmax].
+    ///
+    /// The log-probabilities are in the same order as the candidates.
+    pub fn log_probabilities(&self) -> Vec<f32> {
+        let logits: Vec<f32> = self.candidates.iter().map(|c| c.logit).collect();
+        util::log_softmax(&logits)
+    }
+}
+impl<'a> IntoIterator for &'a Candidates {
+    type Item = &'a Candidate;
+    type IntoIter = std::slice::Iter<'a, Candidate>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A step of a [SamplerChain] that modifies the [Candidates] under consideration,
+/// by changing their logits or removing some of them.
+pub trait SamplerStage: Debug + Send + Sync {
+    /// Given the previous tokens, modify the `candidates`.
+    fn apply(&self, previous_tokens: &[TokenId], candidates: &mut Candidates);
+
+    /// Creates the initial per-session state for this stage. See [Sampler::new_state].
+    fn new_state(&self) -> SamplerState {
+        SamplerState::default()
+    }
+
+    /// Like [SamplerStage::apply], but with access to the per-session `state`.
+    ///
+    /// The default implementation ignores the state and calls [SamplerStage::apply].
+    fn apply_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        candidates: &mut Candidates,
+    ) {
+        let _ = state;
+        self.apply(previous_tokens, candidates)
+    }
+}
+
+/// The final step of a [SamplerChain], which picks the token to use from the [Candidates]
+/// that remain after all of the [SamplerStage]s have been applied.
+pub trait TokenSelector: Debug + Send + Sync {
+    /// Given the remaining `candidates` and a source of randomness, pick a token.
+    fn select(&self, candidates: &Candidates, rng: &mut dyn rand::RngCore) -> TokenId;
+
+    /// Creates the initial per-session state for this selector. See [Sampler::new_state].
+    fn new_state(&self) -> SamplerState {
+        SamplerState::default()
+    }
+
+    /// Like [TokenSelector::select], but with access to the per-session `state`.
+    ///
+    /// The default implementation ignores the state and calls [TokenSelector::select].
+    fn select_with_state(
+        &self,
+        state: &mut SamplerState,
+        candidates: &Candidates,
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let _ = state;
+        self.select(candidates, rng)
+    }
+}
+
+/// A [Sampler] that is composed out of a sequence of [SamplerStage]s, which are applied
+/// in order, followed by a [TokenSelector] that picks the resulting token.
+///
+/// Each of the stages can be used on its own, and custom stages can be added by
+/// implementing [SamplerStage].
+///
+/// ```
+/// use llm_base::samplers::{SampleRandom, SamplerChain, Temperature, TopK, TopP};
+///
+/// let sampler = SamplerChain::new(SampleRandom)
+///     .with(Temperature { temperature: 0.8 })
+///     .with(TopK { k: 40 })
+///     .with(TopP { p: 0.95 });
+/// ```
+#[derive(Debug)]
+pub struct SamplerChain {
+    /// The stages to apply to the candidates, in order.
+    pub stages: Vec<Box<dyn SamplerStage>>,
+    /// The selector that picks the final token.
+    pub selector: Box<dyn TokenSelector>,
+}
+impl SamplerChain {
+    /// Creates a chain with no stages that uses `selector` to pick the final token.
+    pub fn new(selector: impl TokenSelector + 'static) -> Self {
+        Self {
+            stages: vec![],
+            selector: Box::new(selector),
+        }
+    }
+
+    /// Adds `stage` to the end of this chain.
+    pub fn with(mut self, stage: impl SamplerStage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+}
+impl Sampler for SamplerChain {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut self.new_state(), previous_tokens, logits, rng)
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(self.new_chain_state())
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let state = state.get_or_insert_with(|| self.new_chain_state());
+        if state.stages.len() != self.stages.len() {
+            // The stages were changed since the state was created.
+            *state = self.new_chain_state();
+        }
+
+        let mut candidates = Candidates::from_logits(logits);
+        for (stage, stage_state) in self.stages.iter().zip(&mut state.stages) {
+            stage.apply_with_state(stage_state, previous_tokens, &mut candidates);
+        }
+        self.selector
+            .select_with_state(&mut state.selector, &candidates, rng)
+    }
+}
+impl SamplerChain {
+    fn new_chain_state(&self) -> ChainState {
+        ChainState {
+            stages: self.stages.iter().map(|s| s.new_state()).collect(),
+            selector: self.selector.new_state(),
+        }
+    }
+}
+
+/// The per-session state of each part of a [SamplerChain].
+struct ChainState {
+    stages: Vec<SamplerState>,
+    selector: SamplerState,
+}
@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::log;
+
+use crate::{InferenceSession, InferenceSessionConfig, Model, OutputRequest, TokenId};
+
+/// A session of another model that follows the tokens of the session being sampled,
+/// for the stages that combine the logits of several models.
+///
+/// The session is only started once it is first needed.
+#[derive(Default)]
+pub(super) struct CompanionSession {
+    session: Option<InferenceSession>,
+}
+impl CompanionSession {
+    /// Brings the session up to date with `tokens`, and returns the logits that `model`
+    /// predicts after them.
+    ///
+    /// Returns `None` if there are no tokens, or if they could not be evaluated, such as
+    /// when they do not fit in the context window of the model. The latter is logged and
+    /// recorded in `failures`.
+    pub fn logits(
+        &mut self,
+        model: &dyn Model,
+        config: InferenceSessionConfig,
+        tokens: &[TokenId],
+        failures: &FailureCount,
+    ) -> Option<&[f32]> {
+        if tokens.is_empty() {
+            return None;
+        }
+        let session = self
+            .session
+            .get_or_insert_with(|| model.start_session(config));
+
+        // The session is started again if the tokens that differ can't be rewound.
+        let common = session
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let reusable = if common == tokens.len() {
+            common - 1
+        } else {
+            common
+        };
+        if common < session.tokens.len() && (!model.supports_rewind() || reusable == 0) {
+            *session = model.start_session(config);
+        }
+
+        match session.sync_tokens(model, tokens, &mut OutputRequest::default()) {
+            Ok(()) => Some(&session.last_logits),
+            Err(e) => {
+                log::warn!("Failed to evaluate the tokens of a companion model: {e}");
+                failures.0.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+/// The number of times that a [CompanionSession] could not evaluate the tokens, which is
+/// shared by the sessions of a stage.
+#[derive(Debug, Default)]
+pub(super) struct FailureCount(AtomicUsize);
+impl FailureCount {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+impl Clone for FailureCount {
+    fn clone(&self) -> Self {
+        Self(AtomicUsize::new(self.get()))
+    }
+}
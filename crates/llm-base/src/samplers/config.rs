@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Bias, FrequencyPresencePenalty, LocallyTypical, MinP, MirostatV1, MirostatV2, NoRepeatNgram,
+    RepetitionPenalty, SampleGreedy, SampleRandom, SamplerChain, SamplerStage, TailFree,
+    Temperature, TokenSelector, TopK, TopP, TopPTopK,
+};
+
+/// A description of a [SamplerChain] that can be serialized, such as to store generation
+/// presets in configuration files, and that [builds](Self::build) the chain.
+///
+/// Only the stages that don't depend on a model can be described; the others, such as a
+/// [GrammarConstraint](super::GrammarConstraint), can be added to the built chain.
+///
+/// ```
+/// use std::sync::Arc;
+/// use llm_base::{samplers::SamplerConfig, InferenceParameters};
+///
+/// let config: SamplerConfig = serde_json::from_str(r#"{
+///     "stages": [
+///         { "type": "temperature", "temperature": 0.7 },
+///         { "type": "min_p", "p": 0.05 }
+///     ],
+///     "selector": { "type": "random" }
+/// }"#).unwrap();
+/// let parameters = InferenceParameters {
+///     sampler: Arc::new(config.build()),
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplerConfig {
+    /// The stages of the chain, in order.
+    #[serde(default)]
+    pub stages: Vec<SamplerStageConfig>,
+    /// The selector that picks the final token.
+    #[serde(default)]
+    pub selector: TokenSelectorConfig,
+}
+impl Default for SamplerConfig {
+    /// The configuration of [TopPTopK::default].
+    fn default() -> Self {
+        Self::from(&TopPTopK::default())
+    }
+}
+impl From<&TopPTopK> for SamplerConfig {
+    fn from(sampler: &TopPTopK) -> Self {
+        Self {
+            stages: vec![
+                SamplerStageConfig::RepetitionPenalty(RepetitionPenalty {
+                    penalty: sampler.repeat_penalty,
+                    last_n: sampler.repetition_penalty_last_n,
+                }),
+                SamplerStageConfig::Temperature(Temperature {
+                    temperature: sampler.temperature,
+                }),
+                SamplerStageConfig::Bias(Bias {
+                    bias_tokens: sampler.bias_tokens.clone(),
+                }),
+                SamplerStageConfig::TopK(TopK { k: sampler.top_k }),
+                SamplerStageConfig::TopP(TopP { p: sampler.top_p }),
+            ],
+            selector: TokenSelectorConfig::Random,
+        }
+    }
+}
+impl SamplerConfig {
+    /// Builds the [SamplerChain] that this describes.
+    pub fn build(&self) -> SamplerChain {
+        SamplerChain {
+            stages: self.stages.iter().map(SamplerStageConfig::build).collect(),
+            selector: self.selector.build(),
+        }
+    }
+}
+
+/// A description of a [SamplerStage], for a [SamplerConfig].
+///
+/// This is serialized with the name of the stage in snake case as its `type`, along with
+/// the fields of the stage.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SamplerStageConfig {
+    /// [Bias].
+    Bias(Bias),
+    /// [RepetitionPenalty].
+    RepetitionPenalty(RepetitionPenalty),
+    /// [FrequencyPresencePenalty].
+    FrequencyPresencePenalty(FrequencyPresencePenalty),
+    /// [NoRepeatNgram].
+    NoRepeatNgram(NoRepeatNgram),
+    /// [Temperature].
+    Temperature(Temperature),
+    /// [TopK].
+    TopK(TopK),
+    /// [TopP].
+    TopP(TopP),
+    /// [MinP].
+    MinP(MinP),
+    /// [TailFree].
+    TailFree(TailFree),
+    /// [LocallyTypical].
+    LocallyTypical(LocallyTypical),
+}
+impl SamplerStageConfig {
+    /// Builds the stage that this describes.
+    pub fn build(&self) -> Box<dyn SamplerStage> {
+        match self {
+            Self::Bias(s) => Box::new(s.clone()),
+            Self::RepetitionPenalty(s) => Box::new(*s),
+            Self::FrequencyPresencePenalty(s) => Box::new(*s),
+            Self::NoRepeatNgram(s) => Box::new(*s),
+            Self::Temperature(s) => Box::new(*s),
+            Self::TopK(s) => Box::new(*s),
+            Self::TopP(s) => Box::new(*s),
+            Self::MinP(s) => Box::new(*s),
+            Self::TailFree(s) => Box::new(*s),
+            Self::LocallyTypical(s) => Box::new(*s),
+        }
+    }
+}
+
+/// A description of a [TokenSelector], for a [SamplerConfig].
+///
+/// This is serialized like a [SamplerStageConfig].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TokenSelectorConfig {
+    /// [SampleRandom].
+    #[default]
+    Random,
+    /// [SampleGreedy].
+    Greedy,
+    /// [MirostatV1].
+    MirostatV1(MirostatV1),
+    /// [MirostatV2].
+    MirostatV2(MirostatV2),
+}
+impl TokenSelectorConfig {
+    /// Builds the selector that this describes.
+    pub fn build(&self) -> Box<dyn TokenSelector> {
+        match self {
+            Self::Random => Box::new(SampleRandom),
+            Self::Greedy => Box::new(SampleGreedy),
+            Self::MirostatV1(s) => Box::new(*s),
+            Self::MirostatV2(s) => Box::new(*s),
+        }
+    }
+}
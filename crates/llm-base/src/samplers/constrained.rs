@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use super::{
+    Candidates, Grammar, GrammarConstraint, GrammarError, Sampler, SamplerStage, SamplerState,
+};
+use crate::{Regex, TokenId, Tokenizer};
+
+/// A [Sampler] that wraps another `sampler`, only allowing it to pick the tokens that
+/// are kept by a `constraint`, such as a [GrammarConstraint](super::GrammarConstraint).
+///
+/// The logits of the tokens that the constraint removes are set to negative infinity
+/// before the wrapped sampler sees them; the other logits are left as they are. This
+/// allows any sampler to be constrained, not just a [SamplerChain](super::SamplerChain).
+/// A sampler that overrides logits, such as one with a [Bias](super::Bias), can still
+/// pick a removed token; the allowed token with the highest logit is picked instead.
+///
+/// ```
+/// # fn example(model: &dyn llm_base::Model) -> Result<(), llm_base::samplers::GrammarError> {
+/// use std::sync::Arc;
+/// use llm_base::samplers::{ConstrainedSampler, GrammarConstraint, TopPTopK};
+///
+/// let grammar = r#"root ::= [0-9]+"#.parse()?;
+/// let sampler = ConstrainedSampler::new(
+///     GrammarConstraint::new(grammar, model.tokenizer(), model.eot_token_id()),
+///     Arc::new(TopPTopK::default()),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ConstrainedSampler {
+    /// The stage that decides which tokens are allowed.
+    pub constraint: Box<dyn SamplerStage>,
+    /// The sampler that picks from the allowed tokens.
+    pub sampler: Arc<dyn Sampler>,
+}
+impl ConstrainedSampler {
+    /// Wraps `sampler` so that it can only pick the tokens allowed by `constraint`.
+    pub fn new(constraint: impl SamplerStage + 'static, sampler: Arc<dyn Sampler>) -> Self {
+        Self {
+            constraint: Box::new(constraint),
+            sampler,
+        }
+    }
+
+    /// Wraps `sampler` so that the generated text must match `regex`, as described by
+    /// [Grammar::from_regex].
+    ///
+    /// The tokens are taken from the `tokenizer`, and `end_token` is the end-of-text
+    /// token of the model, which is only allowed once the text is a complete match.
+    ///
+    /// ```
+    /// # fn example(model: &dyn llm_base::Model) -> Result<(), llm_base::samplers::GrammarError> {
+    /// use std::sync::Arc;
+    /// use llm_base::{samplers::{ConstrainedSampler, TopPTopK}, Regex};
+    ///
+    /// let sampler = ConstrainedSampler::regex(
+    ///     &Regex::new(r"[A-Z]{3}-[0-9]{4}").unwrap(),
+    ///     model.tokenizer(),
+    ///     model.eot_token_id(),
+    ///     Arc::new(TopPTopK::default()),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn regex(
+        regex: &Regex,
+        tokenizer: &Tokenizer,
+        end_token: TokenId,
+        sampler: Arc<dyn Sampler>,
+    ) -> Result<Self, GrammarError> {
+        let grammar = Grammar::from_regex(regex)?;
+        Ok(Self::new(
+            GrammarConstraint::new(grammar, tokenizer, end_token),
+            sampler,
+        ))
+    }
+
+    fn new_constrained_state(&self) -> ConstrainedState {
+        ConstrainedState {
+            constraint: self.constraint.new_state(),
+            sampler: self.sampler.new_state(),
+        }
+    }
+}
+impl Sampler for ConstrainedSampler {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut self.new_state(), previous_tokens, logits, rng)
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(self.new_constrained_state())
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let state = state.get_or_insert_with(|| self.new_constrained_state());
+
+        let mut candidates = Candidates::from_logits(logits);
+        self.constraint
+            .apply_with_state(&mut state.constraint, previous_tokens, &mut candidates);
+
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        for candidate in &candidates {
+            let id = candidate.id as usize;
+            masked[id] = logits[id];
+        }
+
+        let token =
+            self.sampler
+                .sample_with_state(&mut state.sampler, previous_tokens, &masked, rng);
+        if masked[token as usize] > f32::NEG_INFINITY {
+            return token;
+        }
+        candidates
+            .iter()
+            .max_by(|a, b| a.logit.total_cmp(&b.logit))
+            .map_or(token, |candidate| candidate.id)
+    }
+}
+
+/// The per-session state of the constraint and the wrapped sampler.
+struct ConstrainedState {
+    constraint: SamplerState,
+    sampler: SamplerState,
+}
@@ -0,0 +1,111 @@
+use std::{fmt::Debug, sync::Arc};
+
+use super::{
+    companion::{CompanionSession, FailureCount},
+    Candidates, SamplerStage, SamplerState,
+};
+use crate::{util, InferenceSessionConfig, Model, TokenId};
+
+/// [Contrastive decoding](https://arxiv.org/abs/2210.15097), which prefers the tokens
+/// that the model being sampled (the expert) finds much more likely than a smaller
+/// `amateur` model does.
+///
+/// The tokens so far are also evaluated by the amateur, in its own session, and each
+/// candidate is scored by the difference between the expert's and the amateur's
+/// log-probabilities of it. Only the plausible candidates are kept: those whose
+/// probability is at least `alpha` times that of the most likely candidate. This avoids
+/// the tokens that both models find unlikely, whose difference is meaningless.
+///
+/// The amateur can have any architecture, but must use the same tokenizer as the expert.
+/// This is one of the [stages that evaluate other models](super#stages-that-evaluate-other-models).
+///
+/// The scores are not log-probabilities, so this is typically followed by
+/// [SampleGreedy](super::SampleGreedy) and no other stages:
+///
+/// ```
+/// # fn example(amateur: std::sync::Arc<dyn llm_base::Model>) {
+/// use llm_base::samplers::{ContrastiveDecoding, SampleGreedy, SamplerChain};
+///
+/// let sampler = SamplerChain::new(SampleGreedy).with(ContrastiveDecoding::new(amateur));
+/// # }
+/// ```
+pub struct ContrastiveDecoding {
+    /// The smaller model whose predictions are contrasted with those of the expert.
+    pub amateur: Arc<dyn Model>,
+    /// The minimum probability of a candidate, relative to the most likely candidate,
+    /// for it to be kept.
+    pub alpha: f32,
+    /// The configuration of the amateur's session.
+    pub session_config: InferenceSessionConfig,
+    failures: FailureCount,
+}
+impl ContrastiveDecoding {
+    /// Creates contrastive decoding against `amateur`, with an `alpha` of 0.1.
+    pub fn new(amateur: Arc<dyn Model>) -> Self {
+        Self {
+            amateur,
+            alpha: 0.1,
+            session_config: Default::default(),
+            failures: Default::default(),
+        }
+    }
+
+    /// The number of tokens that the amateur could not evaluate, such as when they no
+    /// longer fit in its context window, in any session. The candidates for those tokens
+    /// were left as they were, and the reasons were logged as warnings.
+    pub fn failures(&self) -> usize {
+        self.failures.get()
+    }
+}
+impl Debug for ContrastiveDecoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContrastiveDecoding")
+            .field("alpha", &self.alpha)
+            .field("session_config", &self.session_config)
+            .field("failures", &self.failures())
+            .finish_non_exhaustive()
+    }
+}
+impl SamplerStage for ContrastiveDecoding {
+    fn apply(&self, _previous_tokens: &[TokenId], _candidates: &mut Candidates) {
+        // The amateur only evaluates the tokens with per-session state.
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(CompanionSession::default())
+    }
+
+    fn apply_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        candidates: &mut Candidates,
+    ) {
+        let amateur = state.get_or_insert_with(CompanionSession::default);
+        let Some(amateur_logits) = amateur.logits(
+            self.amateur.as_ref(),
+            self.session_config,
+            previous_tokens,
+            &self.failures,
+        ) else {
+            return;
+        };
+        let amateur_log_probs = util::log_softmax(amateur_logits);
+
+        let expert_log_probs = candidates.log_probabilities();
+        for (candidate, log_prob) in candidates.as_mut_slice().iter_mut().zip(expert_log_probs) {
+            candidate.logit = log_prob;
+        }
+
+        let max_log_prob = candidates
+            .iter()
+            .map(|c| c.logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let threshold = max_log_prob + self.alpha.ln();
+        candidates.retain(|c| c.logit >= threshold);
+
+        for candidate in candidates.as_mut_slice() {
+            candidate.logit -= amateur_log_probs[candidate.id as usize];
+        }
+    }
+}
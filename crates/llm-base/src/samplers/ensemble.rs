@@ -0,0 +1,140 @@
+use std::{fmt::Debug, sync::Arc};
+
+use super::{
+    companion::{CompanionSession, FailureCount},
+    Candidates, SamplerStage, SamplerState,
+};
+use crate::{util, InferenceSessionConfig, Model, TokenId};
+
+/// A model that contributes to an [Ensemble].
+#[derive(Clone)]
+pub struct EnsembleMember {
+    /// The model, which must share the vocabulary of the model being sampled.
+    pub model: Arc<dyn Model>,
+    /// The weight of the model's log-probabilities.
+    pub weight: f32,
+}
+impl Debug for EnsembleMember {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnsembleMember")
+            .field("weight", &self.weight)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Combines the predictions of several models that share a vocabulary, by replacing the
+/// logit of each candidate with the weighted average of its log-probability under the
+/// model being sampled and under each of the `members`.
+///
+/// Each member evaluates the same tokens as the model being sampled, in its own session.
+/// A member whose session can't evaluate the tokens is left out of the average for that
+/// token, and members with a weight of zero are always left out. This is one of the
+/// [stages that evaluate other models](super#stages-that-evaluate-other-models), and is used like them:
+///
+/// ```
+/// # fn example(other: std::sync::Arc<dyn llm_base::Model>) {
+/// use llm_base::samplers::{Ensemble, EnsembleMember, SampleRandom, SamplerChain, Temperature};
+///
+/// let sampler = SamplerChain::new(SampleRandom)
+///     .with(Ensemble::new(1.0, vec![EnsembleMember { model: other, weight: 0.5 }]))
+///     .with(Temperature { temperature: 0.8 });
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Ensemble {
+    /// The weight of the log-probabilities of the model being sampled.
+    pub weight: f32,
+    /// The other models in the ensemble.
+    pub members: Vec<EnsembleMember>,
+    /// The configuration of the sessions of the members.
+    pub session_config: InferenceSessionConfig,
+    failures: FailureCount,
+}
+impl Ensemble {
+    /// Creates an ensemble of the model being sampled, with the given `weight`, and
+    /// the `members`.
+    pub fn new(weight: f32, members: Vec<EnsembleMember>) -> Self {
+        Self {
+            weight,
+            members,
+            session_config: Default::default(),
+            failures: Default::default(),
+        }
+    }
+
+    /// The number of times that a member could not evaluate the tokens, such as when they
+    /// no longer fit in its context window, in any session. The member was left out of
+    /// the average for those tokens, and the reasons were logged as warnings.
+    pub fn failures(&self) -> usize {
+        self.failures.get()
+    }
+
+    fn new_ensemble_state(&self) -> Vec<CompanionSession> {
+        self.members
+            .iter()
+            .map(|_| CompanionSession::default())
+            .collect()
+    }
+}
+impl SamplerStage for Ensemble {
+    fn apply(&self, _previous_tokens: &[TokenId], _candidates: &mut Candidates) {
+        // The members only evaluate the tokens with per-session state.
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(self.new_ensemble_state())
+    }
+
+    fn apply_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        candidates: &mut Candidates,
+    ) {
+        let sessions = state.get_or_insert_with(|| self.new_ensemble_state());
+        if sessions.len() != self.members.len() {
+            // The members were changed since the state was created.
+            *sessions = self.new_ensemble_state();
+        }
+
+        let mut total_weight = self.weight;
+        let mut member_log_probs = vec![];
+        for (member, session) in self.members.iter().zip(sessions.iter_mut()) {
+            // A log-probability of negative infinity must not be multiplied by zero.
+            if member.weight == 0.0 {
+                continue;
+            }
+            if let Some(logits) = session.logits(
+                member.model.as_ref(),
+                self.session_config,
+                previous_tokens,
+                &self.failures,
+            ) {
+                total_weight += member.weight;
+                member_log_probs.push((member.weight, util::log_softmax(logits)));
+            }
+        }
+        if member_log_probs.is_empty() || total_weight == 0.0 {
+            return;
+        }
+
+        let log_probs = candidates.log_probabilities();
+        for (candidate, log_prob) in candidates.as_mut_slice().iter_mut().zip(log_probs) {
+            // A removed candidate has a log-probability of negative infinity, which must not
+            // be multiplied by a weight of zero.
+            let mut combined = if self.weight == 0.0 {
+                0.0
+            } else {
+                self.weight * log_prob
+            };
+            for (weight, log_probs) in &member_log_probs {
+                let log_prob = log_probs
+                    .get(candidate.id as usize)
+                    .copied()
+                    .unwrap_or(f32::NEG_INFINITY);
+                combined += weight * log_prob;
+            }
+            candidate.logit = combined / total_weight;
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use super::GrammarError;
+
+/// The rules for JSON values that are not further constrained by the schema.
+const PRIMITIVES: &[(&str, &str)] = &[
+    ("space", r#"" "?"#),
+    ("boolean", r#"("true" | "false") space"#),
+    ("null", r#""null" space"#),
+    ("integer", r#""-"? ("0" | [1-9] [0-9]*) space"#),
+    (
+        "number",
+        r#""-"? ("0" | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? space"#,
+    ),
+    (
+        "string",
+        r#""\"" ([^"\\\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]))* "\"" space"#,
+    ),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+    (
+        "object",
+        r#""{" space (string ":" space value ("," space string ":" space value)*)? "}" space"#,
+    ),
+    (
+        "array",
+        r#""[" space (value ("," space value)*)? "]" space"#,
+    ),
+];
+
+/// Keywords that restrict the values a schema accepts in ways that cannot be
+/// expressed by the conversion. Rather than generate values that may not conform
+/// to the schema, these are rejected.
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "allOf",
+    "not",
+    "if",
+    "pattern",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "multipleOf",
+    "patternProperties",
+    "propertyNames",
+    "minProperties",
+    "maxProperties",
+    "prefixItems",
+    "contains",
+    "uniqueItems",
+];
+
+/// Converts a JSON Schema into the source of a GBNF grammar that only matches JSON
+/// values that conform to it.
+pub(super) fn to_gbnf(schema: &Value) -> Result<String, GrammarError> {
+    let mut converter = Converter {
+        root: schema,
+        rules: vec![],
+        refs: HashMap::new(),
+    };
+    converter.visit(schema, "root")?;
+    for (name, body) in PRIMITIVES {
+        converter.rules.push((name.to_string(), body.to_string()));
+    }
+
+    Ok(converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{name} ::= {body}\n"))
+        .collect())
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    /// The rules created for each `$ref`, so that recursive schemas refer back to them.
+    refs: HashMap<String, String>,
+}
+impl<'a> Converter<'a> {
+    /// Adds a rule matching `schema`, and returns its name.
+    ///
+    /// Every rule other than `root` has a `-` in its name, so that they do not
+    /// clash with the primitive rules.
+    fn visit(&mut self, schema: &'a Value, name: &str) -> Result<String, GrammarError> {
+        let name = self.reserve(name);
+        let body = self.body(schema, &name)?;
+        self.define(&name, body);
+        Ok(name)
+    }
+
+    fn body(&mut self, schema: &'a Value, name: &str) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(schema) => schema,
+            _ => return Err(unsupported(format!("the schema {schema}"))),
+        };
+
+        if let Some(keyword) = UNSUPPORTED_KEYWORDS
+            .iter()
+            .find(|keyword| schema.contains_key(**keyword))
+        {
+            return Err(unsupported(format!("the `{keyword}` keyword")));
+        }
+
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| unsupported("a non-string `$ref`"))?;
+            return self.reference(reference);
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(format!("{} space", literal(&value.to_string())));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| unsupported("a non-array `enum`"))?;
+            let alternatives: Vec<_> = values
+                .iter()
+                .map(|value| literal(&value.to_string()))
+                .collect();
+            return Ok(format!("({}) space", alternatives.join(" | ")));
+        }
+
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| unsupported(format!("a non-array `{keyword}`")))?;
+                let alternatives = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, schema)| self.visit(schema, &format!("{name}-{i}")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(alternatives.join(" | "));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.typed(schema, ty, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|ty| {
+                        let ty = ty
+                            .as_str()
+                            .ok_or_else(|| unsupported(format!("the type {ty}")))?;
+                        self.typed(schema, ty, name)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(ty) => Err(unsupported(format!("the type {ty}"))),
+            None if schema.contains_key("properties") => self.typed(schema, "object", name),
+            None if schema.contains_key("items") => self.typed(schema, "array", name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        ty: &str,
+        name: &str,
+    ) -> Result<String, GrammarError> {
+        match ty {
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.to_string()),
+            "object" => self.object(schema, name),
+            "array" => self.array(schema, name),
+            _ => Err(unsupported(format!("the type `{ty}`"))),
+        }
+    }
+
+    fn object(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String, GrammarError> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok("object".to_string());
+        };
+        let properties = properties
+            .as_object()
+            .ok_or_else(|| unsupported("a non-object `properties`"))?;
+        let required: Vec<&str> = match schema.get("required") {
+            Some(required) => required
+                .as_array()
+                .ok_or_else(|| unsupported("a non-array `required`"))?
+                .iter()
+                .map(|r| {
+                    r.as_str()
+                        .ok_or_else(|| unsupported("a non-string `required`"))
+                })
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        if let Some(missing) = required.iter().find(|r| !properties.contains_key(**r)) {
+            return Err(unsupported(format!(
+                "the required property `{missing}` without a schema"
+            )));
+        }
+
+        // Properties are always generated in the order of the map, with the
+        // required ones first.
+        let mut mandatory = vec![];
+        let mut optional = vec![];
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{name}-{key}"))?;
+            let pair = format!(
+                "{} space \":\" space {value}",
+                literal(&Value::from(key.as_str()).to_string())
+            );
+            if required.contains(&key.as_str()) {
+                mandatory.push(pair);
+            } else {
+                optional.push(pair);
+            }
+        }
+
+        let mut body = String::from("\"{\" space");
+        if !mandatory.is_empty() {
+            body += &format!(" {}", mandatory.join(" \",\" space "));
+            for pair in &optional {
+                body += &format!(" (\",\" space {pair})?");
+            }
+        } else if !optional.is_empty() {
+            // The first property that is present is not preceded by a comma.
+            let alternatives: Vec<String> = (0..optional.len())
+                .map(|first| {
+                    let mut alternative = optional[first].clone();
+                    for pair in &optional[first + 1..] {
+                        alternative += &format!(" (\",\" space {pair})?");
+                    }
+                    alternative
+                })
+                .collect();
+            body += &format!(" ({})?", alternatives.join(" | "));
+        }
+        body += " \"}\" space";
+        Ok(body)
+    }
+
+    fn array(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String, GrammarError> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items, &format!("{name}-item"))?,
+            None => "value".to_string(),
+        };
+        let min_items = match schema.get("minItems") {
+            Some(n) => n
+                .as_u64()
+                .ok_or_else(|| unsupported(format!("the `minItems` value {n}")))?,
+            None => 0,
+        };
+        let max_items = match schema.get("maxItems") {
+            Some(n) => Some(
+                n.as_u64()
+                    .ok_or_else(|| unsupported(format!("the `maxItems` value {n}")))?,
+            ),
+            None => None,
+        };
+
+        // The items after the first one, each preceded by a comma.
+        let rest = |count: u64, max: Option<u64>| {
+            let mut rest = vec![format!("\",\" space {item}"); count as usize].join(" ");
+            match max {
+                None => rest += &format!(" (\",\" space {item})*"),
+                Some(max) => {
+                    let mut optional = String::new();
+                    for _ in count..max {
+                        optional = format!("(\",\" space {item} {optional})?");
+                    }
+                    rest += &format!(" {optional}");
+                }
+            }
+            rest
+        };
+
+        let items = match (min_items, max_items) {
+            (_, Some(0)) => String::new(),
+            (0, max) => format!("({item} {})?", rest(0, max.map(|m| m - 1))),
+            (min, max) => format!("{item} {}", rest(min - 1, max.map(|m| m - 1))),
+        };
+        Ok(format!("\"[\" space {items} \"]\" space"))
+    }
+
+    fn reference(&mut self, reference: &str) -> Result<String, GrammarError> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| unsupported(format!("the reference `{reference}`")))?;
+
+        // The rule is registered before visiting the target, so that it can refer to itself.
+        let last = reference.rsplit('/').next().unwrap_or_default();
+        let name = self.reserve(&format!("ref-{last}"));
+        self.refs.insert(reference.to_string(), name.clone());
+        let body = self.body(target, &name)?;
+        self.define(&name, body);
+        Ok(name)
+    }
+
+    /// Reserves a unique rule name based on `name`.
+    fn reserve(&mut self, name: &str) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.rules.iter().any(|(r, _)| *r == name) {
+            name = format!("{base}-{n}");
+            n += 1;
+        }
+        self.rules.push((name.clone(), String::new()));
+        name
+    }
+
+    fn define(&mut self, name: &str, body: String) {
+        if let Some(rule) = self.rules.iter_mut().find(|(r, _)| r == name) {
+            rule.1 = body;
+        }
+    }
+}
+
+/// A GBNF string literal that matches `text` exactly.
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\x{:02X}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn unsupported(what: impl Into<String>) -> GrammarError {
+    GrammarError::UnsupportedSchema(what.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::Grammar;
+
+    #[test]
+    fn test_object_properties() {
+        let grammar = Grammar::from_json_schema(&json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "maxItems": 2 }
+            },
+            "required": ["name"]
+        }))
+        .unwrap();
+
+        assert!(grammar.matches(r#"{"name": "x\"y"}"#));
+        assert!(grammar.matches(r#"{"name": "x", "age": -3}"#));
+        assert!(grammar.matches(r#"{"name": "x", "tags": ["a", "b"]}"#));
+        assert!(!grammar.matches(r#"{"age": 3}"#));
+        assert!(!grammar.matches(r#"{"name": "x", "age": 1.5}"#));
+        assert!(!grammar.matches(r#"{"name": "x", "tags": ["a", "b", "a"]}"#));
+        assert!(!grammar.matches(r#"{"name": "x", "tags": ["c"]}"#));
+    }
+
+    #[test]
+    fn test_recursive_reference() {
+        let grammar = Grammar::from_json_schema(&json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(grammar.matches(r#"{}"#));
+        assert!(grammar.matches(r#"{"children": [{}, {"children": []}]}"#));
+        assert!(!grammar.matches(r#"{"children": [1]}"#));
+    }
+}
@@ -0,0 +1,780 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use thiserror::Error;
+
+use super::{Candidates, SamplerStage, SamplerState};
+use crate::{TokenId, Tokenizer};
+
+mod json_schema;
+mod parse;
+mod regular_expression;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// Errors encountered when parsing a [Grammar].
+pub enum GrammarError {
+    #[error("syntax error on line {line}: {message}")]
+    /// The grammar is not valid GBNF.
+    Syntax {
+        /// The line the error was found on, starting from 1.
+        line: usize,
+        /// A description of the error.
+        message: String,
+    },
+    #[error("the grammar does not define a `root` rule")]
+    /// The grammar does not have a `root` rule to start from.
+    MissingRoot,
+    #[error("the rule `{0}` is used, but never defined")]
+    /// A rule is referenced but never defined.
+    UndefinedRule(String),
+    #[error("the rule `{0}` is left-recursive")]
+    /// A rule can refer to itself without consuming any input first, which
+    /// cannot be matched incrementally.
+    LeftRecursion(String),
+    #[error("the JSON schema uses {0}, which is not supported")]
+    /// The JSON schema uses a feature that cannot be converted into a grammar.
+    UnsupportedSchema(String),
+    #[error("the regular expression is invalid: {0}")]
+    /// The regular expression could not be parsed.
+    InvalidRegex(String),
+    #[error("the regular expression uses {0}, which is not supported")]
+    /// The regular expression uses a feature that cannot be converted into a grammar.
+    UnsupportedRegex(String),
+}
+
+/// A formal grammar in the GBNF format used by llama.cpp, which is used by
+/// [GrammarConstraint] to constrain generation.
+///
+/// A grammar is a list of rules of the form `name ::= alternatives`, where each
+/// alternative is a sequence of:
+/// - string literals: `"text"`, with the escapes `\n`, `\r`, `\t`, `\\`, `\"`,
+///   `\xHH`, `\uHHHH` and `\UHHHHHHHH`
+/// - character classes: `[a-z]`, or `[^"\n]` for every character except those listed
+/// - any character: `.`
+/// - references to other rules: `name`
+/// - groups: `( alternatives )`
+/// - repetitions of the preceding item: `*` (zero or more), `+` (one or more) and
+///   `?` (optional)
+///
+/// Alternatives are separated by `|`, rules end at a newline that is not inside a
+/// group or after a `|`, and `#` starts a comment. Generation starts with the
+/// rule named `root`.
+///
+/// ```
+/// use llm_base::samplers::Grammar;
+///
+/// let grammar: Grammar = r#"
+///     root ::= row ("\n" row)*
+///     row  ::= cell ("," cell)*
+///     cell ::= [a-z0-9]+
+/// "#
+/// .parse()
+/// .unwrap();
+/// assert!(grammar.matches("a,b\n1,2"));
+/// assert!(!grammar.matches("a,,b"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grammar {
+    rules: Vec<Rule>,
+    root: usize,
+}
+impl Grammar {
+    /// Parses a grammar in the GBNF format.
+    pub fn parse(source: &str) -> Result<Self, GrammarError> {
+        parse::parse(source)
+    }
+
+    /// Creates a grammar that only matches JSON values that conform to the JSON `schema`.
+    ///
+    /// The types, `properties`, `required`, `items`, `minItems`, `maxItems`, `enum`,
+    /// `const`, `anyOf`, `oneOf` and local `$ref`s are supported. Properties are
+    /// generated with the required ones first, each in the order of the schema's
+    /// `properties` map; this is alphabetical unless the `preserve_order` feature
+    /// of `serde_json` is enabled. Schemas
+    /// using keywords that further restrict values, such as `pattern` or `minimum`,
+    /// are rejected, as the generated values could not be guaranteed to conform.
+    ///
+    /// ```
+    /// use llm_base::samplers::Grammar;
+    ///
+    /// let schema = serde_json::json!({
+    ///     "type": "object",
+    ///     "properties": { "answer": { "type": "boolean" } },
+    ///     "required": ["answer"]
+    /// });
+    /// let grammar = Grammar::from_json_schema(&schema).unwrap();
+    /// assert!(grammar.matches(r#"{"answer": true}"#));
+    /// ```
+    pub fn from_json_schema(schema: &serde_json::Value) -> Result<Self, GrammarError> {
+        Self::parse(&json_schema::to_gbnf(schema)?)
+    }
+
+    /// Creates a grammar that matches the same text as `regex`.
+    ///
+    /// The whole generated text must match, so `^` and `$` are implied. Flags set
+    /// inside the pattern, such as `(?i)`, are respected, but options set with a
+    /// `RegexBuilder` are not, as they are not part of the pattern. Other look-around
+    /// assertions, such as `\b`, are not supported.
+    ///
+    /// ```
+    /// use llm_base::{samplers::Grammar, Regex};
+    ///
+    /// let grammar = Grammar::from_regex(&Regex::new(r"\+?[0-9]{2,3}( [0-9]{3,4})+").unwrap()).unwrap();
+    /// assert!(grammar.matches("+44 1234 567"));
+    /// assert!(!grammar.matches("call +44 1234 567"));
+    /// ```
+    pub fn from_regex(regex: &crate::Regex) -> Result<Self, GrammarError> {
+        regular_expression::to_grammar(regex.as_str())
+    }
+
+    /// Returns whether `text` is a complete match of this grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.accept(&stacks, c as u32);
+        }
+        stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// The stacks of positions to match before any input has been accepted.
+    ///
+    /// Each stack holds the positions to continue with once the rule on top of it
+    /// has been matched. After expansion, the top of every stack is a character to
+    /// match, and an empty stack means that the input so far is a complete match.
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut stacks = HashSet::new();
+        for (alternative, sequence) in self.rules[self.root].alternatives.iter().enumerate() {
+            let mut stack = vec![];
+            if !sequence.is_empty() {
+                stack.push(Position {
+                    rule: self.root,
+                    alternative,
+                    element: 0,
+                });
+            }
+            self.expand(stack, &mut stacks);
+        }
+        stacks.into_iter().collect()
+    }
+
+    /// Replaces rule references on top of `stack` with each of their alternatives,
+    /// until every resulting stack has a character to match on top.
+    fn expand(&self, mut stack: Stack, out: &mut HashSet<Stack>) {
+        let Some(&top) = stack.last() else {
+            out.insert(stack);
+            return;
+        };
+
+        let rule = match self.element(top) {
+            Element::Chars { .. } => {
+                out.insert(stack);
+                return;
+            }
+            &Element::Rule(rule) => rule,
+        };
+
+        stack.pop();
+        if let Some(next) = self.next(top) {
+            stack.push(next);
+        }
+        for (alternative, sequence) in self.rules[rule].alternatives.iter().enumerate() {
+            let mut stack = stack.clone();
+            if !sequence.is_empty() {
+                stack.push(Position {
+                    rule,
+                    alternative,
+                    element: 0,
+                });
+            }
+            self.expand(stack, out);
+        }
+    }
+
+    /// Advances each of the `stacks` past the character `c`, dropping those that
+    /// cannot accept it.
+    fn accept(&self, stacks: &[Stack], c: u32) -> Vec<Stack> {
+        let mut out = HashSet::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if !self.element(top).matches(c, c) {
+                continue;
+            }
+
+            let mut stack = stack.clone();
+            stack.pop();
+            if let Some(next) = self.next(top) {
+                stack.push(next);
+            }
+            self.expand(stack, &mut out);
+        }
+        out.into_iter().collect()
+    }
+
+    /// Advances the `stacks` past the UTF-8 `bytes`, following on from the `partial`
+    /// bytes of an incomplete character. Returns the new stacks and any trailing
+    /// incomplete character, or `None` if the bytes cannot be accepted.
+    fn accept_bytes(
+        &self,
+        stacks: &[Stack],
+        partial: &[u8],
+        bytes: &[u8],
+    ) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let mut stacks = stacks.to_vec();
+        let mut partial = partial.to_vec();
+        for &byte in bytes {
+            partial.push(byte);
+            if let CharProgress::Accepted(next) = self.accept_char_bytes(&stacks, &partial)? {
+                stacks = next;
+                partial.clear();
+            }
+        }
+        Some((stacks, partial))
+    }
+
+    /// Advances the `stacks` past the `bytes` of a single UTF-8 encoded character,
+    /// which may be incomplete. Returns `None` if the character cannot be accepted,
+    /// or could not be completed into one that is.
+    fn accept_char_bytes(&self, stacks: &[Stack], bytes: &[u8]) -> Option<CharProgress> {
+        let len = utf8_len(bytes[0])?;
+        if bytes.len() < len {
+            let (low, high) = utf8_partial_range(bytes)?;
+            let possible = stacks.iter().any(|s| {
+                s.last()
+                    .map_or(false, |&p| self.element(p).matches(low, high))
+            });
+            return possible.then_some(CharProgress::Incomplete);
+        }
+
+        let c = std::str::from_utf8(bytes).ok()?.chars().next()?;
+        let stacks = self.accept(stacks, c as u32);
+        (!stacks.is_empty()).then_some(CharProgress::Accepted(stacks))
+    }
+
+    fn element(&self, position: Position) -> &Element {
+        &self.rules[position.rule].alternatives[position.alternative][position.element]
+    }
+
+    /// The position after `position` in the same sequence, if there is one.
+    fn next(&self, position: Position) -> Option<Position> {
+        let sequence = &self.rules[position.rule].alternatives[position.alternative];
+        (position.element + 1 < sequence.len()).then_some(Position {
+            element: position.element + 1,
+            ..position
+        })
+    }
+
+    /// Rejects grammars where a rule can reach itself without consuming any input,
+    /// as expanding them would never terminate.
+    fn check_left_recursion(&self) -> Result<(), GrammarError> {
+        // Find the rules that can match the empty string.
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, rule) in self.rules.iter().enumerate() {
+                if !nullable[i]
+                    && rule.alternatives.iter().any(|sequence| {
+                        sequence
+                            .iter()
+                            .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                    })
+                {
+                    nullable[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // The rules that can be expanded from each rule without consuming input.
+        let leftmost: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let mut out = vec![];
+                for sequence in &rule.alternatives {
+                    for element in sequence {
+                        match element {
+                            Element::Chars { .. } => break,
+                            &Element::Rule(r) => {
+                                out.push(r);
+                                if !nullable[r] {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+                out
+            })
+            .collect();
+
+        // Depth-first search for a cycle.
+        fn visit(rule: usize, leftmost: &[Vec<usize>], state: &mut [u8]) -> Result<(), usize> {
+            const VISITING: u8 = 1;
+            const DONE: u8 = 2;
+            match state[rule] {
+                VISITING => return Err(rule),
+                DONE => return Ok(()),
+                _ => {}
+            }
+            state[rule] = VISITING;
+            for &next in &leftmost[rule] {
+                visit(next, leftmost, state)?;
+            }
+            state[rule] = DONE;
+            Ok(())
+        }
+
+        let mut state = vec![0; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            visit(rule, &leftmost, &mut state)
+                .map_err(|r| GrammarError::LeftRecursion(self.rules[r].name.clone()))?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for Grammar {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    name: String,
+    alternatives: Vec<Vec<Element>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Element {
+    /// A single character within one of the inclusive `ranges`, or outside all of
+    /// them if `negated` is set.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// A reference to another rule.
+    Rule(usize),
+}
+impl Element {
+    /// Returns whether this element matches any of the characters from `low` to `high`.
+    fn matches(&self, low: u32, high: u32) -> bool {
+        let Element::Chars { ranges, negated } = self else {
+            return false;
+        };
+
+        if !negated {
+            return ranges
+                .iter()
+                .any(|&(start, end)| start as u32 <= high && low <= end as u32);
+        }
+
+        // Look for a character in the range that none of the ranges cover.
+        let mut c = low;
+        while let Some(&(_, end)) = ranges
+            .iter()
+            .find(|&&(start, end)| start as u32 <= c && c <= end as u32)
+        {
+            c = end as u32 + 1;
+            if c > high {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Position {
+    rule: usize,
+    alternative: usize,
+    element: usize,
+}
+
+type Stack = Vec<Position>;
+
+/// The progress of [Grammar::accept_char_bytes] through a character.
+enum CharProgress {
+    /// The character is incomplete, but can still be completed into one that is accepted.
+    Incomplete,
+    /// The character was accepted, leaving these stacks.
+    Accepted(Vec<Stack>),
+}
+
+/// The length of a UTF-8 encoded character, given its first byte.
+fn utf8_len(first: u8) -> Option<usize> {
+    match first {
+        0x00..=0x7F => Some(1),
+        0xC0..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF7 => Some(4),
+        _ => None,
+    }
+}
+
+/// The range of characters that the start of an incomplete UTF-8 encoded character
+/// could be completed into.
+fn utf8_partial_range(bytes: &[u8]) -> Option<(u32, u32)> {
+    let len = utf8_len(bytes[0])?;
+    let mut value = u32::from(bytes[0]) & (0x7F >> len);
+    for &b in &bytes[1..] {
+        if b & 0xC0 != 0x80 {
+            return None;
+        }
+        value = (value << 6) | u32::from(b & 0x3F);
+    }
+    let missing_bits = 6 * (len - bytes.len()) as u32;
+    let low = value << missing_bits;
+    Some((low, low | ((1 << missing_bits) - 1)))
+}
+
+/// A [SamplerStage] that only keeps the tokens that can continue the generated text
+/// in a way that matches a [Grammar].
+///
+/// The end-of-text token is only kept once the generated text is a complete match.
+/// This should be the first stage of a [SamplerChain](super::SamplerChain), so that
+/// the other stages only consider valid tokens:
+///
+/// ```
+/// # fn example(model: &dyn llm_base::Model) -> Result<(), llm_base::samplers::GrammarError> {
+/// use llm_base::samplers::{GrammarConstraint, SampleRandom, SamplerChain, Temperature};
+///
+/// let grammar = r#"root ::= "yes" | "no""#.parse()?;
+/// let sampler = SamplerChain::new(SampleRandom)
+///     .with(GrammarConstraint::new(grammar, model.tokenizer(), model.eot_token_id()))
+///     .with(Temperature { temperature: 0.8 });
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The generated text is tracked in the per-session state, starting from the first
+/// token sampled after the state was created; for an
+/// [InferenceSession](crate::InferenceSession), this is the start of each call to
+/// [infer](crate::InferenceSession::infer). If tokens are removed from the session,
+/// the generated text is matched again from the start. Without any state, the
+/// constraint applies as if nothing has been generated yet.
+///
+/// If no token can continue the text, only the end-of-text token is kept.
+pub struct GrammarConstraint {
+    grammar: Grammar,
+    vocabulary: Vec<Vec<u8>>,
+    trie: TokenTrie,
+    end_token: TokenId,
+}
+impl GrammarConstraint {
+    /// Creates a constraint for `grammar` over the vocabulary of `tokenizer`, where
+    /// `end_token` is the end-of-text token of the model.
+    pub fn new(grammar: Grammar, tokenizer: &Tokenizer, end_token: TokenId) -> Self {
+        Self::from_vocabulary(
+            grammar,
+            (0..tokenizer.len()).map(|id| tokenizer.token(id)).collect(),
+            end_token,
+        )
+    }
+
+    /// Creates a constraint for `grammar` over the `vocabulary`, where each entry is
+    /// the bytes of the token with that ID.
+    pub fn from_vocabulary(grammar: Grammar, vocabulary: Vec<Vec<u8>>, end_token: TokenId) -> Self {
+        Self {
+            grammar,
+            trie: TokenTrie::new(&vocabulary),
+            vocabulary,
+            end_token,
+        }
+    }
+
+    /// The grammar that the generated text must match.
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    fn initial_state(&self, start: usize) -> ConstraintState {
+        ConstraintState {
+            start,
+            tokens: vec![],
+            stacks: self.grammar.initial_stacks(),
+            partial: vec![],
+        }
+    }
+
+    /// Brings the state up to date with the tokens that have been generated.
+    fn update(&self, state: &mut ConstraintState, previous_tokens: &[TokenId]) {
+        let start = state.start.min(previous_tokens.len());
+        let generated = &previous_tokens[start..];
+        if start != state.start || !generated.starts_with(&state.tokens) {
+            // Tokens were removed or replaced, so start over.
+            *state = self.initial_state(start);
+        }
+
+        for &token in &generated[state.tokens.len()..] {
+            state.tokens.push(token);
+            if token == self.end_token {
+                continue;
+            }
+            match self.accept_token(state, token) {
+                Some((stacks, partial)) => {
+                    state.stacks = stacks;
+                    state.partial = partial;
+                }
+                None => state.stacks.clear(),
+            }
+        }
+    }
+
+    fn accept_token(
+        &self,
+        state: &ConstraintState,
+        token: TokenId,
+    ) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let bytes = self.vocabulary.get(token as usize)?;
+        if bytes.is_empty() {
+            return None;
+        }
+        self.grammar
+            .accept_bytes(&state.stacks, &state.partial, bytes)
+    }
+
+    fn constrain(&self, state: &ConstraintState, candidates: &mut Candidates) {
+        let complete = state.partial.is_empty() && state.stacks.iter().any(|s| s.is_empty());
+
+        let mut accepted = vec![false; self.vocabulary.len()];
+        if !state.stacks.is_empty() {
+            let mut partial = state.partial.clone();
+            self.accept_trie(0, &state.stacks, &mut partial, &mut accepted);
+        }
+
+        let mut allowed = candidates.clone();
+        allowed.retain(|c| {
+            if c.id == self.end_token {
+                complete
+            } else {
+                accepted.get(c.id as usize).copied().unwrap_or(false)
+            }
+        });
+
+        if allowed.is_empty() {
+            candidates.retain(|c| c.id == self.end_token);
+        } else {
+            *candidates = allowed;
+        }
+    }
+
+    /// Marks the tokens below `node` in the trie that can follow the `stacks` and the
+    /// `partial` bytes of an incomplete character as `accepted`. The tokens that share
+    /// a prefix are advanced past it once, and the branches that cannot be accepted
+    /// are not visited.
+    fn accept_trie(
+        &self,
+        node: usize,
+        stacks: &[Stack],
+        partial: &mut Vec<u8>,
+        accepted: &mut [bool],
+    ) {
+        for &(byte, child) in &self.trie.nodes[node].children {
+            partial.push(byte);
+            match self.grammar.accept_char_bytes(stacks, partial) {
+                Some(CharProgress::Incomplete) => {
+                    self.trie.mark(child, accepted);
+                    self.accept_trie(child, stacks, partial, accepted);
+                }
+                Some(CharProgress::Accepted(next)) => {
+                    self.trie.mark(child, accepted);
+                    self.accept_trie(child, &next, &mut vec![], accepted);
+                }
+                None => {}
+            }
+            partial.pop();
+        }
+    }
+}
+impl fmt::Debug for GrammarConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrammarConstraint")
+            .field("grammar", &self.grammar)
+            .field("vocabulary_len", &self.vocabulary.len())
+            .field("end_token", &self.end_token)
+            .finish()
+    }
+}
+impl SamplerStage for GrammarConstraint {
+    fn apply(&self, previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        self.constrain(&self.initial_state(previous_tokens.len()), candidates);
+    }
+
+    fn apply_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        candidates: &mut Candidates,
+    ) {
+        let state = state.get_or_insert_with(|| self.initial_state(previous_tokens.len()));
+        self.update(state, previous_tokens);
+        self.constrain(state, candidates);
+    }
+}
+
+/// The tokens of a vocabulary, arranged by their bytes so that the tokens that share a
+/// prefix can be matched against a grammar together.
+struct TokenTrie {
+    /// The nodes of the trie, starting with the root, which is the empty prefix.
+    nodes: Vec<TrieNode>,
+}
+impl TokenTrie {
+    fn new(vocabulary: &[Vec<u8>]) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in vocabulary.iter().enumerate() {
+            let mut node = 0;
+            for &byte in bytes {
+                let existing = nodes[node].children.iter().find(|&&(b, _)| b == byte);
+                node = match existing {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as TokenId);
+        }
+        Self { nodes }
+    }
+
+    /// Marks the tokens that end at `node` as `accepted`.
+    fn mark(&self, node: usize, accepted: &mut [bool]) {
+        for &token in &self.nodes[node].tokens {
+            accepted[token as usize] = true;
+        }
+    }
+}
+
+#[derive(Default)]
+struct TrieNode {
+    /// The byte that leads to each child, and the index of the child.
+    children: Vec<(u8, usize)>,
+    /// The tokens whose bytes end at this node.
+    tokens: Vec<TokenId>,
+}
+
+/// The progress of a [GrammarConstraint] through the generated text.
+struct ConstraintState {
+    /// The index of the first generated token in the previous tokens.
+    start: usize,
+    /// The tokens that have been generated so far.
+    tokens: Vec<TokenId>,
+    /// The stacks of the grammar after the generated text. If this is empty, the text
+    /// can no longer match the grammar.
+    stacks: Vec<Stack>,
+    /// The bytes of an incomplete character at the end of the generated text.
+    partial: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Grammar::parse("start ::= \"a\""),
+            Err(GrammarError::MissingRoot)
+        );
+        assert_eq!(
+            Grammar::parse("root ::= item"),
+            Err(GrammarError::UndefinedRule("item".to_string()))
+        );
+        assert_eq!(
+            Grammar::parse("root ::= root \"a\" | \"b\""),
+            Err(GrammarError::LeftRecursion("root".to_string()))
+        );
+        assert!(matches!(
+            Grammar::parse("root ::= \"a"),
+            Err(GrammarError::Syntax { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_matches() {
+        let grammar: Grammar = r#"
+            # A comment
+            root   ::= ("-"? digit+ | "none") [^0-9]?
+            digit  ::= [0-9]
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(grammar.matches("42"));
+        assert!(grammar.matches("-7x"));
+        assert!(grammar.matches("none"));
+        assert!(!grammar.matches("-"));
+        assert!(!grammar.matches("4-2"));
+        assert!(!grammar.matches("none1"));
+    }
+
+    #[test]
+    fn test_repeated_literals() {
+        let grammar: Grammar = r#"root ::= "ab"* "-" "cd"+"#.parse().unwrap();
+
+        assert!(grammar.matches("-cd"));
+        assert!(grammar.matches("abab-cdcd"));
+        assert!(!grammar.matches("abbb-cd"));
+        assert!(!grammar.matches("ab-cdd"));
+        assert!(!grammar.matches("ab-"));
+    }
+
+    #[test]
+    fn test_constraint_masks_tokens() {
+        let grammar = r#"root ::= "ab" "é"*"#.parse().unwrap();
+        let vocabulary = vec![
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"b".to_vec(),
+            vec![0xC3],
+            vec![0xA9],
+            vec![],
+        ];
+        let constraint = GrammarConstraint::from_vocabulary(grammar, vocabulary, 5);
+        let mut state = SamplerState::default();
+        let allowed = |state: &mut SamplerState, previous_tokens: &[TokenId]| {
+            let mut candidates = Candidates::from_logits(&[0.0; 6]);
+            constraint.apply_with_state(state, previous_tokens, &mut candidates);
+            candidates.iter().map(|c| c.id).collect::<Vec<_>>()
+        };
+
+        // The first token is a prompt token that is not generated.
+        assert_eq!(allowed(&mut state, &[2]), [0, 1]);
+        assert_eq!(allowed(&mut state, &[2, 0]), [2]);
+        assert_eq!(allowed(&mut state, &[2, 0, 2]), [3, 5]);
+        assert_eq!(allowed(&mut state, &[2, 0, 2, 3]), [4]);
+        assert_eq!(allowed(&mut state, &[2, 0, 2, 3, 4]), [3, 5]);
+
+        // Replacing generated tokens matches again from the start.
+        assert_eq!(allowed(&mut state, &[2, 1]), [3, 5]);
+    }
+
+    #[test]
+    fn test_constraint_matches_tokens_with_shared_prefixes() {
+        let grammar = r#"root ::= "ab" ("c" | "é") "d""#.parse().unwrap();
+        let vocabulary = vec![
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abc".to_vec(),
+            b"abcd".to_vec(),
+            b"abd".to_vec(),
+            b"b".to_vec(),
+            "abé".as_bytes().to_vec(),
+            vec![b'a', b'b', 0xC3],
+            b"x".to_vec(),
+        ];
+        let constraint = GrammarConstraint::from_vocabulary(grammar, vocabulary, 8);
+
+        let mut candidates = Candidates::from_logits(&[0.0; 9]);
+        constraint.apply(&[], &mut candidates);
+        let allowed: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(allowed, [0, 1, 2, 3, 6, 7]);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+
+use super::{Element, Grammar, GrammarError, Rule};
+
+/// Parses a grammar in the GBNF format.
+pub(super) fn parse(source: &str) -> Result<Grammar, GrammarError> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+        line: 1,
+        builder: Builder::default(),
+    };
+
+    parser.skip_space(true);
+    while !parser.at_end() {
+        parser.parse_rule()?;
+        parser.skip_space(true);
+    }
+
+    parser.builder.finish()
+}
+
+/// Collects the rules of a grammar as they are parsed. Rules can be referenced
+/// before they are defined, so each one is given its index on first sight.
+#[derive(Default)]
+struct Builder {
+    indices: HashMap<String, usize>,
+    names: Vec<String>,
+    definitions: Vec<Option<Vec<Vec<Element>>>>,
+}
+impl Builder {
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        let index = self.names.len();
+        self.indices.insert(name.to_owned(), index);
+        self.names.push(name.to_owned());
+        self.definitions.push(None);
+        index
+    }
+
+    /// Creates an anonymous rule for a group or repetition, named after the rule
+    /// it appears in.
+    fn generated(&mut self, base: usize, alternatives: Vec<Vec<Element>>) -> usize {
+        let mut n = self.names.len();
+        let name = loop {
+            let name = format!("{}_{}", self.names[base], n);
+            if !self.indices.contains_key(&name) {
+                break name;
+            }
+            n += 1;
+        };
+        let index = self.symbol(&name);
+        self.definitions[index] = Some(alternatives);
+        index
+    }
+
+    fn finish(self) -> Result<Grammar, GrammarError> {
+        let root = *self.indices.get("root").ok_or(GrammarError::MissingRoot)?;
+        let rules = self
+            .names
+            .into_iter()
+            .zip(self.definitions)
+            .map(|(name, alternatives)| match alternatives {
+                Some(alternatives) => Ok(Rule { name, alternatives }),
+                None => Err(GrammarError::UndefinedRule(name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let grammar = Grammar { rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    builder: Builder,
+}
+impl Parser {
+    fn parse_rule(&mut self) -> Result<(), GrammarError> {
+        let name = self.parse_name()?;
+        let index = self.builder.symbol(&name);
+        if self.builder.definitions[index].is_some() {
+            return Err(self.error(format!("rule `{name}` is defined more than once")));
+        }
+
+        self.skip_space(false);
+        if !self.eat_str("::=") {
+            return Err(self.error("expected `::=`"));
+        }
+        self.skip_space(true);
+
+        let alternatives = self.parse_alternatives(index, false)?;
+        match self.peek() {
+            None | Some('\n') | Some('\r') => {}
+            Some(c) => return Err(self.error(format!("unexpected `{c}`"))),
+        }
+
+        self.builder.definitions[index] = Some(alternatives);
+        Ok(())
+    }
+
+    fn parse_alternatives(
+        &mut self,
+        rule: usize,
+        nested: bool,
+    ) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alternatives = vec![self.parse_sequence(rule, nested)?];
+        while self.eat('|') {
+            self.skip_space(true);
+            alternatives.push(self.parse_sequence(rule, nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, rule: usize, nested: bool) -> Result<Vec<Element>, GrammarError> {
+        let mut sequence = vec![];
+        // The start of the last item in the sequence, which repetition operators apply to.
+        let mut last_start = None;
+
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    let mut literal = vec![];
+                    loop {
+                        match self.peek() {
+                            None | Some('\n') => return Err(self.error("unterminated string")),
+                            Some('"') => break,
+                            Some(_) => literal.push(self.parse_char()?),
+                        }
+                    }
+                    self.pos += 1;
+
+                    // A repetition operator applies to the whole literal.
+                    last_start = (!literal.is_empty()).then_some(sequence.len());
+                    for c in literal {
+                        sequence.push(Element::Chars {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                }
+                '[' => {
+                    self.pos += 1;
+                    let negated = self.eat('^');
+                    let mut ranges = vec![];
+                    loop {
+                        match self.peek() {
+                            None | Some('\n') => {
+                                return Err(self.error("unterminated character class"))
+                            }
+                            Some(']') => break,
+                            Some(_) => {
+                                let start = self.parse_char()?;
+                                let end =
+                                    if self.peek() == Some('-') && self.peek_at(1) != Some(']') {
+                                        self.pos += 1;
+                                        self.parse_char()?
+                                    } else {
+                                        start
+                                    };
+                                ranges.push((start, end));
+                            }
+                        }
+                    }
+                    self.pos += 1;
+
+                    last_start = Some(sequence.len());
+                    sequence.push(Element::Chars { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = Some(sequence.len());
+                    sequence.push(Element::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.parse_alternatives(rule, true)?;
+                    if !self.eat(')') {
+                        return Err(self.error("expected `)`"));
+                    }
+
+                    last_start = Some(sequence.len());
+                    sequence.push(Element::Rule(self.builder.generated(rule, alternatives)));
+                }
+                '*' | '+' | '?' => {
+                    self.pos += 1;
+                    let start = last_start
+                        .ok_or_else(|| self.error(format!("`{c}` must follow an item")))?;
+                    let item: Vec<Element> = sequence.drain(start..).collect();
+
+                    // Repetitions are rewritten into recursive rules:
+                    //   item* => r ::= item r |
+                    //   item+ => r ::= item r | item
+                    //   item? => r ::= item |
+                    let index = self.builder.generated(rule, vec![]);
+                    let mut repeated = item.clone();
+                    repeated.push(Element::Rule(index));
+                    self.builder.definitions[index] = Some(match c {
+                        '*' => vec![repeated, vec![]],
+                        '+' => vec![repeated, item],
+                        _ => vec![item, vec![]],
+                    });
+
+                    sequence.push(Element::Rule(index));
+                }
+                c if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    last_start = Some(sequence.len());
+                    sequence.push(Element::Rule(self.builder.symbol(&name)));
+                }
+                '|' | ')' | '\n' | '\r' => break,
+                c => return Err(self.error(format!("unexpected `{c}`"))),
+            }
+            self.skip_space(nested);
+        }
+
+        Ok(sequence)
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().map_or(false, is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Parses a single, possibly escaped, character in a string or character class.
+    fn parse_char(&mut self) -> Result<char, GrammarError> {
+        let c = self
+            .next()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        if c != '\\' {
+            return Ok(c);
+        }
+
+        let escape = self
+            .next()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        let digits = match escape {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            '\\' | '"' | '[' | ']' | '-' | '^' => return Ok(escape),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Err(self.error(format!("unknown escape `\\{escape}`"))),
+        };
+
+        let mut value = 0;
+        for _ in 0..digits {
+            let digit = self
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("expected a hexadecimal digit"))?;
+            value = value * 16 + digit;
+        }
+        char::from_u32(value).ok_or_else(|| self.error(format!("invalid code point {value:#x}")))
+    }
+
+    /// Skips whitespace and comments. Newlines are only skipped if `newlines` is set,
+    /// as they end a rule.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => {
+                    self.next();
+                }
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let len = s.chars().count();
+        if self.chars[self.pos..]
+            .iter()
+            .take(len)
+            .copied()
+            .eq(s.chars())
+        {
+            self.pos += len;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> GrammarError {
+        GrammarError::Syntax {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
@@ -0,0 +1,157 @@
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+
+use super::{Element, Grammar, GrammarError, Rule};
+
+/// Converts a regular expression into a grammar that matches the same strings.
+pub(super) fn to_grammar(pattern: &str) -> Result<Grammar, GrammarError> {
+    let hir =
+        regex_syntax::parse(pattern).map_err(|e| GrammarError::InvalidRegex(e.to_string()))?;
+
+    let mut converter = Converter { rules: vec![] };
+    let root = converter.rule("root");
+    let mut sequence = vec![];
+    converter.sequence(&hir, &mut sequence)?;
+    converter.rules[root].alternatives = vec![sequence];
+
+    let grammar = Grammar {
+        rules: converter.rules,
+        root,
+    };
+    grammar
+        .check_left_recursion()
+        .map_err(|_| unsupported("a repetition of an expression that can be empty"))?;
+    Ok(grammar)
+}
+
+struct Converter {
+    rules: Vec<Rule>,
+}
+impl Converter {
+    /// Adds the elements that match `hir` to the end of `out`.
+    fn sequence(&mut self, hir: &Hir, out: &mut Vec<Element>) -> Result<(), GrammarError> {
+        match hir.kind() {
+            HirKind::Empty => {}
+            HirKind::Literal(literal) => {
+                let text = std::str::from_utf8(&literal.0)
+                    .map_err(|_| unsupported("a literal that is not valid UTF-8"))?;
+                out.extend(text.chars().map(|c| Element::Chars {
+                    ranges: vec![(c, c)],
+                    negated: false,
+                }));
+            }
+            HirKind::Class(Class::Unicode(class)) => out.push(Element::Chars {
+                ranges: class
+                    .ranges()
+                    .iter()
+                    .map(|r| (r.start(), r.end()))
+                    .collect(),
+                negated: false,
+            }),
+            HirKind::Class(Class::Bytes(class)) => {
+                if !class.is_ascii() {
+                    return Err(unsupported("a class of non-ASCII bytes"));
+                }
+                out.push(Element::Chars {
+                    ranges: class
+                        .ranges()
+                        .iter()
+                        .map(|r| (char::from(r.start()), char::from(r.end())))
+                        .collect(),
+                    negated: false,
+                });
+            }
+            // The whole text is always matched, so these are implied.
+            HirKind::Look(Look::Start | Look::End) => {}
+            HirKind::Look(_) => return Err(unsupported("a look-around assertion")),
+            HirKind::Repetition(repetition) => {
+                let mut item = vec![];
+                self.sequence(&repetition.sub, &mut item)?;
+
+                for _ in 0..repetition.min {
+                    out.extend(item.iter().cloned());
+                }
+                match repetition.max {
+                    // r ::= item r |
+                    None => {
+                        let rule = self.rule("repeat");
+                        let mut repeated = item;
+                        repeated.push(Element::Rule(rule));
+                        self.rules[rule].alternatives = vec![repeated, vec![]];
+                        out.push(Element::Rule(rule));
+                    }
+                    // Each optional item is nested in the previous one:
+                    // r_1 ::= item r_2 | ; r_2 ::= item r_3 | ; ...
+                    Some(max) => {
+                        let mut tail = None;
+                        for _ in repetition.min..max {
+                            let rule = self.rule("optional");
+                            let mut optional = item.clone();
+                            optional.extend(tail.map(Element::Rule));
+                            self.rules[rule].alternatives = vec![optional, vec![]];
+                            tail = Some(rule);
+                        }
+                        out.extend(tail.map(Element::Rule));
+                    }
+                }
+            }
+            HirKind::Capture(capture) => self.sequence(&capture.sub, out)?,
+            HirKind::Concat(hirs) => {
+                for hir in hirs {
+                    self.sequence(hir, out)?;
+                }
+            }
+            HirKind::Alternation(hirs) => {
+                let rule = self.rule("alternation");
+                let mut alternatives = vec![];
+                for hir in hirs {
+                    let mut sequence = vec![];
+                    self.sequence(hir, &mut sequence)?;
+                    alternatives.push(sequence);
+                }
+                self.rules[rule].alternatives = alternatives;
+                out.push(Element::Rule(rule));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an empty rule, and returns its index.
+    fn rule(&mut self, kind: &str) -> usize {
+        let index = self.rules.len();
+        self.rules.push(Rule {
+            name: format!("{kind}-{index}"),
+            alternatives: vec![],
+        });
+        index
+    }
+}
+
+fn unsupported(what: &str) -> GrammarError {
+    GrammarError::UnsupportedRegex(what.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Grammar;
+    use crate::Regex;
+
+    #[test]
+    fn test_regex_matches() {
+        let grammar =
+            Grammar::from_regex(&Regex::new(r"^(\d{4})-(0[1-9]|1[0-2])-\d{2}( [AP]M)?$").unwrap())
+                .unwrap();
+        assert!(grammar.matches("2023-07-01"));
+        assert!(grammar.matches("1999-12-31 PM"));
+        assert!(!grammar.matches("2023-13-01"));
+        assert!(!grammar.matches("2023-07-01 "));
+
+        let grammar = Grammar::from_regex(&Regex::new("(?i)(yes|no)+").unwrap()).unwrap();
+        assert!(grammar.matches("YesnoNO"));
+        assert!(!grammar.matches(""));
+    }
+
+    #[test]
+    fn test_regex_rejects_look_around() {
+        assert!(Grammar::from_regex(&Regex::new(r"\bword\b").unwrap()).is_err());
+    }
+}
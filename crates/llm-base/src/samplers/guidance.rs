@@ -0,0 +1,132 @@
+use std::{fmt::Debug, sync::Arc};
+
+use super::{
+    companion::{CompanionSession, FailureCount},
+    Candidates, SamplerStage, SamplerState,
+};
+use crate::{InferenceSessionConfig, Model, TokenId, TokenizationError};
+
+/// [Classifier-free guidance](https://arxiv.org/abs/2306.17806), which steers generation
+/// away from a negative prompt.
+///
+/// The tokens generated so far are also evaluated after the `negative_prompt`, in a second
+/// session of the `model`, and each logit is replaced with
+/// `uncond + scale * (cond - uncond)`, where `cond` is the logit from the session being
+/// sampled and `uncond` is the one from the negative prompt. A `scale` of 1.0 has no
+/// effect, and larger values move further away from the negative prompt.
+///
+/// The generated tokens are the ones that follow the tokens that were present when the
+/// per-session state was created, which is at the start of each call to
+/// [InferenceSession::infer](crate::InferenceSession::infer). This is one of the
+/// [stages that evaluate other models](super#stages-that-evaluate-other-models), and is used like them:
+///
+/// ```
+/// # fn example(model: std::sync::Arc<dyn llm_base::Model>) -> Result<(), llm_base::TokenizationError> {
+/// use llm_base::samplers::{ClassifierFreeGuidance, SampleRandom, SamplerChain, Temperature};
+///
+/// let sampler = SamplerChain::new(SampleRandom)
+///     .with(ClassifierFreeGuidance::new(model, "A rude and unhelpful answer:", 1.5)?)
+///     .with(Temperature { temperature: 0.8 });
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClassifierFreeGuidance {
+    /// The model that evaluates the negative prompt, which is usually the model that is
+    /// generating.
+    pub model: Arc<dyn Model>,
+    /// The tokens of the negative prompt.
+    pub negative_prompt: Vec<TokenId>,
+    /// The strength of the guidance.
+    pub scale: f32,
+    /// The configuration of the session that evaluates the negative prompt.
+    pub session_config: InferenceSessionConfig,
+    failures: FailureCount,
+}
+impl ClassifierFreeGuidance {
+    /// Creates guidance away from `negative_prompt`, which is tokenized as the start of
+    /// a session of `model`.
+    pub fn new(
+        model: Arc<dyn Model>,
+        negative_prompt: &str,
+        scale: f32,
+    ) -> Result<Self, TokenizationError> {
+        let negative_prompt = model
+            .tokenizer()
+            .tokenize(negative_prompt, true)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+        Ok(Self {
+            model,
+            negative_prompt,
+            scale,
+            session_config: Default::default(),
+            failures: Default::default(),
+        })
+    }
+
+    /// The number of tokens for which the negative prompt could not be evaluated, such as
+    /// when it no longer fits in the context window, in any session. The candidates for
+    /// those tokens were left as they were, and the reasons were logged as warnings.
+    pub fn failures(&self) -> usize {
+        self.failures.get()
+    }
+}
+impl Debug for ClassifierFreeGuidance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClassifierFreeGuidance")
+            .field("negative_prompt", &self.negative_prompt)
+            .field("scale", &self.scale)
+            .field("session_config", &self.session_config)
+            .field("failures", &self.failures())
+            .finish_non_exhaustive()
+    }
+}
+impl SamplerStage for ClassifierFreeGuidance {
+    fn apply(&self, _previous_tokens: &[TokenId], _candidates: &mut Candidates) {
+        // The negative prompt is only evaluated with per-session state.
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(GuidanceState::default())
+    }
+
+    fn apply_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        candidates: &mut Candidates,
+    ) {
+        let state = state.get_or_insert_with(GuidanceState::default);
+        let prompt_len = *state.prompt_len.get_or_insert(previous_tokens.len());
+        let generated = &previous_tokens[prompt_len.min(previous_tokens.len())..];
+
+        let tokens: Vec<_> = self
+            .negative_prompt
+            .iter()
+            .chain(generated)
+            .copied()
+            .collect();
+        let Some(uncond) = state.negative.logits(
+            self.model.as_ref(),
+            self.session_config,
+            &tokens,
+            &self.failures,
+        ) else {
+            return;
+        };
+
+        for candidate in candidates.as_mut_slice() {
+            let uncond = uncond[candidate.id as usize];
+            candidate.logit = uncond + self.scale * (candidate.logit - uncond);
+        }
+    }
+}
+
+/// The per-session state of [ClassifierFreeGuidance].
+#[derive(Default)]
+struct GuidanceState {
+    /// The number of tokens before the first generated token.
+    prompt_len: Option<usize>,
+    negative: CompanionSession,
+}
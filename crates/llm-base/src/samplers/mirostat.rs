@@ -0,0 +1,176 @@
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+
+use super::{Candidates, SamplerState, TokenSelector};
+use crate::TokenId;
+
+/// [Mirostat](https://arxiv.org/abs/2007.14966) version 1.
+///
+/// Mirostat dynamically adjusts the number of tokens it samples from so that the
+/// "surprise" (perplexity) of the generated text stays close to the target `tau`. Unlike a fixed
+/// top-K or top-P setting, this keeps the quality of the output stable over long generations.
+///
+/// As it learns from each token it selects, it keeps per-session state; when used without
+/// a session, every token is selected as if it were the first.
+///
+/// This is used as the final step of a [SamplerChain](super::SamplerChain), and is typically
+/// preceded by [Temperature](super::Temperature) and no truncation stages:
+///
+/// ```
+/// use llm_base::samplers::{MirostatV1, SamplerChain, Temperature};
+///
+/// let sampler = SamplerChain::new(MirostatV1::default()).with(Temperature { temperature: 0.8 });
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MirostatV1 {
+    /// The target surprise (cross-entropy) of the generated text.
+    pub tau: f32,
+    /// The learning rate used to update the running estimate after each token.
+    pub eta: f32,
+    /// The number of the most probable tokens used to estimate the distribution of the
+    /// model's predictions.
+    pub m: usize,
+}
+impl Default for MirostatV1 {
+    fn default() -> Self {
+        Self {
+            tau: 5.0,
+            eta: 0.1,
+            m: 100,
+        }
+    }
+}
+impl TokenSelector for MirostatV1 {
+    fn select(&self, candidates: &Candidates, rng: &mut dyn rand::RngCore) -> TokenId {
+        self.select_with_state(&mut self.new_state(), candidates, rng)
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(MirostatState::new(self.tau))
+    }
+
+    fn select_with_state(
+        &self,
+        state: &mut SamplerState,
+        candidates: &Candidates,
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let state = state.get_or_insert_with(|| MirostatState::new(self.tau));
+
+        let mut candidates = candidates.clone();
+        candidates.sort();
+        let probs = candidates.probabilities();
+
+        // Estimate s_hat, the exponent of the Zipf distribution that the most probable
+        // `m` tokens follow.
+        let mut sum_ti_bi = 0.0;
+        let mut sum_ti_sq = 0.0;
+        for i in 0..self.m.saturating_sub(1).min(probs.len().saturating_sub(1)) {
+            let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+            let b_i = (probs[i] / probs[i + 1]).ln();
+            sum_ti_bi += t_i * b_i;
+            sum_ti_sq += t_i * t_i;
+        }
+        let s_hat = sum_ti_bi / sum_ti_sq;
+
+        // Compute k from the estimated s_hat and the target surprise value.
+        let epsilon_hat = s_hat - 1.0;
+        let n = candidates.len() as f32;
+        let k =
+            ((epsilon_hat * 2f32.powf(state.mu)) / (1.0 - n.powf(-epsilon_hat))).powf(1.0 / s_hat);
+        if k.is_finite() {
+            candidates.keep_top((k as usize).max(1));
+        }
+
+        state.select_and_update(&candidates, self.tau, self.eta, rng)
+    }
+}
+
+/// [Mirostat](https://arxiv.org/abs/2007.14966) version 2.
+///
+/// A simpler variant of [MirostatV1] that does not estimate the distribution of the
+/// model's predictions, and instead discards every token whose surprise is above the
+/// running estimate.
+///
+/// As it learns from each token it selects, it keeps per-session state; when used without
+/// a session, every token is selected as if it were the first.
+///
+/// ```
+/// use llm_base::samplers::{MirostatV2, SamplerChain, Temperature};
+///
+/// let sampler = SamplerChain::new(MirostatV2::default()).with(Temperature { temperature: 0.8 });
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MirostatV2 {
+    /// The target surprise (cross-entropy) of the generated text.
+    pub tau: f32,
+    /// The learning rate used to update the running estimate after each token.
+    pub eta: f32,
+}
+impl Default for MirostatV2 {
+    fn default() -> Self {
+        Self { tau: 5.0, eta: 0.1 }
+    }
+}
+impl TokenSelector for MirostatV2 {
+    fn select(&self, candidates: &Candidates, rng: &mut dyn rand::RngCore) -> TokenId {
+        self.select_with_state(&mut self.new_state(), candidates, rng)
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(MirostatState::new(self.tau))
+    }
+
+    fn select_with_state(
+        &self,
+        state: &mut SamplerState,
+        candidates: &Candidates,
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let state = state.get_or_insert_with(|| MirostatState::new(self.tau));
+
+        let mut candidates = candidates.clone();
+        candidates.sort();
+        let probs = candidates.probabilities();
+
+        // Discard the tokens that are more surprising than the running estimate,
+        // but always keep the most likely token.
+        let keep = probs
+            .iter()
+            .position(|p| -p.log2() > state.mu)
+            .unwrap_or(probs.len())
+            .max(1);
+        candidates.truncate(keep);
+
+        state.select_and_update(&candidates, self.tau, self.eta, rng)
+    }
+}
+
+/// The running estimate shared by both versions of Mirostat.
+struct MirostatState {
+    /// The maximum surprise value; starts out at twice the target surprise.
+    mu: f32,
+}
+impl MirostatState {
+    fn new(tau: f32) -> Self {
+        Self { mu: 2.0 * tau }
+    }
+
+    /// Samples a token from the remaining `candidates`, and updates the estimate with
+    /// how surprising that token was.
+    fn select_and_update(
+        &mut self,
+        candidates: &Candidates,
+        tau: f32,
+        eta: f32,
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let probs = candidates.probabilities();
+        let dist = WeightedIndex::new(&probs).expect("WeightedIndex error");
+        let idx = dist.sample(rng);
+
+        let observed_surprise = -probs[idx].log2();
+        self.mu -= eta * (observed_surprise - tau);
+
+        candidates.as_slice()[idx].id
+    }
+}
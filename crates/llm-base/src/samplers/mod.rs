@@ -0,0 +1,440 @@
+//! Defines the samplers used for generation.
+//!
+//! You can define your own [Sampler] by implementing the trait, or compose one out of
+//! reusable stages with a [SamplerChain]. Custom stages can be added to a chain by
+//! implementing [SamplerStage].
+//!
+//! # Stages that evaluate other models
+//!
+//! [ClassifierFreeGuidance], [ContrastiveDecoding] and [Ensemble] evaluate the tokens of the
+//! session being sampled in sessions of other models, which are kept in the per-session
+//! state of the stage. Without that state, when they are applied with [SamplerStage::apply],
+//! they leave the candidates as they are, as every token would have to be evaluated again.
+//! They also leave the candidates as they are when the other sessions can't evaluate the
+//! tokens, such as when their context window is full; this is logged as a warning and
+//! counted by their `failures` method, such as [ClassifierFreeGuidance::failures].
+//!
+//! As these stages combine the logits of the model being sampled with those of the other
+//! models, they should come before any stage that changes the logits, such as
+//! [Temperature].
+
+use std::{any::Any, fmt::Debug};
+
+use crate::{TokenBias, TokenId};
+
+mod chain;
+pub use chain::*;
+mod companion;
+mod config;
+pub use config::*;
+mod constrained;
+pub use constrained::*;
+mod contrastive;
+pub use contrastive::*;
+mod ensemble;
+pub use ensemble::*;
+mod grammar;
+pub use grammar::*;
+mod guidance;
+pub use guidance::*;
+mod mirostat;
+pub use mirostat::*;
+mod selectors;
+pub use selectors::*;
+mod stages;
+pub use stages::*;
+
+/// A sampler for generation.
+///
+/// Samplers are shared between sessions and are not mutated during sampling. Samplers that
+/// need to keep track of state between tokens, like [MirostatV2], create that state with
+/// [Sampler::new_state]; each [InferenceSession](crate::InferenceSession) then holds on to it
+/// and passes it to [Sampler::sample_with_state] for every token it infers.
+pub trait Sampler: Debug + Send + Sync {
+    /// Given the previous tokens, the logits from the most recent evaluation, and a source of randomness,
+    /// sample from the logits and return the token ID.
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId;
+
+    /// Creates the initial per-session state for this sampler.
+    ///
+    /// Stateless samplers do not need to implement this.
+    fn new_state(&self) -> SamplerState {
+        SamplerState::default()
+    }
+
+    /// Like [Sampler::sample], but with access to the per-session `state` that was created
+    /// with [Sampler::new_state].
+    ///
+    /// The default implementation ignores the state and calls [Sampler::sample].
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let _ = state;
+        self.sample(previous_tokens, logits, rng)
+    }
+}
+
+/// Mutable state that a [Sampler] (or a part of one) keeps between tokens.
+///
+/// This is opaque to everything but the sampler that created it.
+#[derive(Default)]
+pub struct SamplerState(Option<Box<dyn Any + Send>>);
+impl SamplerState {
+    /// Creates a new state holding `state`.
+    pub fn new<T: Any + Send>(state: T) -> Self {
+        Self(Some(Box::new(state)))
+    }
+
+    /// Returns the state if it is of type `T`.
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.0.as_mut().and_then(|s| s.downcast_mut())
+    }
+
+    /// Returns the state if it is of type `T`, replacing it with the result of `f` otherwise.
+    pub fn get_or_insert_with<T: Any + Send>(&mut self, f: impl FnOnce() -> T) -> &mut T {
+        if self.get_mut::<T>().is_none() {
+            *self = Self::new(f());
+        }
+        self.get_mut().unwrap()
+    }
+}
+impl Debug for SamplerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SamplerState")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Top-P Top-K sampling.
+///
+/// A standard sampler that uses top-K sampling (the top-K tokens with the highest
+/// probability are considered) and top-P sampling (only tokens with a cumulative
+/// probability of `P` are considered).
+///
+/// It also implements [CTRL](https://arxiv.org/abs/1909.05858)'s repetition penalty,
+/// and the ability to bias the generation of individual tokens.
+///
+/// This is a preset for a [SamplerChain]; see [TopPTopK::chain] for the stages it uses.
+/// The chain is built with the per-session state, so it is built once for each session
+/// rather than for each token.
+#[derive(Clone, Debug)]
+pub struct TopPTopK {
+    /// The top K words by score are kept during sampling.
+    pub top_k: usize,
+    /// The cumulative probability after which no more words are kept for sampling.
+    pub top_p: f32,
+    /// The penalty for repeating tokens. Higher values make the generation less
+    /// likely to get into a loop, but may harm results when repetitive outputs
+    /// are desired.
+    pub repeat_penalty: f32,
+    /// Temperature (randomness) used for sampling. A higher number is more random.
+    pub temperature: f32,
+    /// A list of tokens to bias against in the process of generation.
+    pub bias_tokens: TokenBias,
+    /// The number of tokens to consider for the repetition penalty.
+    pub repetition_penalty_last_n: usize,
+}
+impl Default for TopPTopK {
+    fn default() -> Self {
+        Self {
+            top_k: 40,
+            top_p: 0.95,
+            repeat_penalty: 1.30,
+            temperature: 0.80,
+            bias_tokens: TokenBias::empty(),
+            repetition_penalty_last_n: 512,
+        }
+    }
+}
+impl TopPTopK {
+    /// Creates the [SamplerChain] that this sampler is equivalent to.
+    pub fn chain(&self) -> SamplerChain {
+        SamplerConfig::from(self).build()
+    }
+}
+impl Sampler for TopPTopK {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut self.new_state(), previous_tokens, logits, rng)
+    }
+
+    fn new_state(&self) -> SamplerState {
+        SamplerState::new(self.new_chain_state())
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let state = state.get_or_insert_with(|| self.new_chain_state());
+        state
+            .chain
+            .sample_with_state(&mut state.state, previous_tokens, logits, rng)
+    }
+}
+impl TopPTopK {
+    fn new_chain_state(&self) -> TopPTopKState {
+        let chain = self.chain();
+        TopPTopKState {
+            state: chain.new_state(),
+            chain,
+        }
+    }
+}
+
+/// The chain that a [TopPTopK] is equivalent to, and the state of that chain.
+struct TopPTopKState {
+    chain: SamplerChain,
+    state: SamplerState,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::mock_model::MockModel;
+
+    #[test]
+    fn test_top_k_keeps_highest_logits_in_order() {
+        let mut candidates = Candidates::from_logits(&[0.1, 3.0, -1.0, 2.0, 0.5]);
+        TopK { k: 3 }.apply(&[], &mut candidates);
+
+        let ids: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [1, 3, 4]);
+        assert!(candidates.is_sorted());
+    }
+
+    #[test]
+    fn test_top_p_keeps_at_least_one_candidate() {
+        let mut candidates = Candidates::from_logits(&[10.0, 0.0, 0.0]);
+        TopP { p: 0.5 }.apply(&[], &mut candidates);
+
+        let ids: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0]);
+    }
+
+    #[test]
+    fn test_repetition_penalty_reduces_both_signs() {
+        let mut candidates = Candidates::from_logits(&[2.0, -2.0, 2.0]);
+        RepetitionPenalty {
+            penalty: 2.0,
+            last_n: 8,
+        }
+        .apply(&[0, 1], &mut candidates);
+
+        let logits: Vec<_> = candidates.iter().map(|c| c.logit).collect();
+        assert_eq!(logits, [1.0, -4.0, 2.0]);
+    }
+
+    #[test]
+    fn test_sampler_config_round_trip() {
+        use rand::SeedableRng;
+
+        let config = SamplerConfig {
+            selector: TokenSelectorConfig::MirostatV2(MirostatV2::default()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#"{"type":"top_k","k":40}"#));
+        assert_eq!(
+            serde_json::from_str::<SamplerConfig>(&json).unwrap(),
+            config
+        );
+
+        // The default configuration picks the same tokens as the default sampler.
+        let logits = [0.5, 3.0, 1.0, -2.0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let token = SamplerConfig::default()
+            .build()
+            .sample(&[1], &logits, &mut rng);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(token, TopPTopK::default().sample(&[1], &logits, &mut rng));
+    }
+
+    #[test]
+    fn test_frequency_presence_penalty_counts_occurrences() {
+        let mut candidates = Candidates::from_logits(&[1.0, 1.0, 1.0]);
+        FrequencyPresencePenalty {
+            frequency_penalty: 0.5,
+            presence_penalty: 0.25,
+            last_n: 3,
+        }
+        .apply(&[1, 0, 0, 1], &mut candidates);
+
+        let logits: Vec<_> = candidates.iter().map(|c| c.logit).collect();
+        assert_eq!(logits, [-0.25, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn test_no_repeat_ngram_bans_completions() {
+        let mut candidates = Candidates::from_logits(&[0.0; 5]);
+        NoRepeatNgram { size: 3 }.apply(&[1, 2, 3, 1, 2, 4, 1, 2], &mut candidates);
+
+        let ids: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_penalties_keep_a_candidate() {
+        use rand::SeedableRng;
+
+        // Only the repeated token can be picked, as if the others were masked by a grammar.
+        let logits = [f32::NEG_INFINITY, 1.0, f32::NEG_INFINITY];
+        let mut candidates = Candidates::from_logits(&logits);
+        NoRepeatNgram { size: 2 }.apply(&[1, 1], &mut candidates);
+        assert_eq!(
+            candidates.iter().map(|c| c.id).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+
+        let mut candidates = Candidates::from_logits(&logits);
+        FrequencyPresencePenalty {
+            frequency_penalty: 0.0,
+            presence_penalty: f32::INFINITY,
+            last_n: 4,
+        }
+        .apply(&[1], &mut candidates);
+        assert_eq!(
+            candidates.iter().map(|c| c.logit).collect::<Vec<_>>(),
+            logits
+        );
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(SampleRandom.select(&candidates, &mut rng), 1);
+    }
+
+    #[test]
+    fn test_constrained_sampler_ignores_bias_of_removed_tokens() {
+        use rand::SeedableRng;
+
+        let sampler = ConstrainedSampler::new(
+            TopK { k: 1 },
+            std::sync::Arc::new(SamplerChain::new(SampleGreedy).with(Bias {
+                bias_tokens: TokenBias::new(vec![(2, 100.0)]),
+            })),
+        );
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(sampler.sample(&[], &[0.0, 5.0, 1.0], &mut rng), 1);
+    }
+
+    #[test]
+    fn test_min_p_is_relative_to_most_likely() {
+        let mut candidates = Candidates::from_logits(&[-1.0, 0.0, -3.0]);
+        MinP { p: 0.2 }.apply(&[], &mut candidates);
+
+        let ids: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0, 1]);
+    }
+
+    #[test]
+    fn test_locally_typical_can_drop_most_likely() {
+        let logits: Vec<f32> = [0.4f32, 0.3, 0.3].iter().map(|p| p.ln()).collect();
+        let mut candidates = Candidates::from_logits(&logits);
+        LocallyTypical { p: 0.5 }.apply(&[], &mut candidates);
+
+        let ids: Vec<_> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[test]
+    fn test_bias_overrides_scaled_logit() {
+        let sampler = TopPTopK {
+            top_k: 1,
+            temperature: 0.5,
+            bias_tokens: TokenBias::new(vec![(2, 100.0)]),
+            ..Default::default()
+        };
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        assert_eq!(sampler.sample(&[], &[1.0, 5.0, 0.0], &mut rng), 2);
+    }
+
+    #[test]
+    fn test_guidance_with_empty_control_tokens() {
+        let model = Arc::new(MockModel::with_empty_control_tokens(4));
+        let guidance = ClassifierFreeGuidance::new(model.clone(), "ab", 2.0).unwrap();
+        assert_eq!(guidance.negative_prompt, [1, 3, 4]);
+
+        // The negative prompt predicts `c`, which the guidance moves away from.
+        let mut state = guidance.new_state();
+        let mut candidates = Candidates::from_logits(&[0.0; 29]);
+        guidance.apply_with_state(&mut state, &[7], &mut candidates);
+        assert_eq!(candidates.iter().find(|c| c.id == 5).unwrap().logit, -1.0);
+        assert_eq!(guidance.failures(), 0);
+
+        // The generated tokens no longer fit in the context window of the negative prompt.
+        let mut candidates = Candidates::from_logits(&[0.0; 29]);
+        guidance.apply_with_state(&mut state, &[7, 8, 9], &mut candidates);
+        assert!(candidates.iter().all(|c| c.logit == 0.0));
+        assert_eq!(guidance.failures(), 1);
+    }
+
+    #[test]
+    fn test_custom_stage_in_chain() {
+        #[derive(Debug)]
+        struct BanEven;
+        impl SamplerStage for BanEven {
+            fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+                candidates.retain(|c| c.id % 2 == 1);
+            }
+        }
+
+        let sampler = SamplerChain::new(SampleGreedy).with(BanEven);
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        assert_eq!(sampler.sample(&[], &[9.0, 1.0, 8.0, 2.0], &mut rng), 3);
+    }
+
+    #[test]
+    fn test_chain_keeps_selector_state() {
+        #[derive(Debug)]
+        struct RoundRobin;
+        impl TokenSelector for RoundRobin {
+            fn select(&self, candidates: &Candidates, rng: &mut dyn rand::RngCore) -> TokenId {
+                self.select_with_state(&mut self.new_state(), candidates, rng)
+            }
+
+            fn new_state(&self) -> SamplerState {
+                SamplerState::new(0usize)
+            }
+
+            fn select_with_state(
+                &self,
+                state: &mut SamplerState,
+                candidates: &Candidates,
+                _rng: &mut dyn rand::RngCore,
+            ) -> TokenId {
+                let count = state.get_or_insert_with(|| 0usize);
+                let id = candidates.as_slice()[*count % candidates.len()].id;
+                *count += 1;
+                id
+            }
+        }
+
+        let sampler = SamplerChain::new(RoundRobin).with(TopK { k: 2 });
+        let mut state = sampler.new_state();
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        let logits = [0.0, 2.0, 1.0];
+        let sampled: Vec<_> = (0..3)
+            .map(|_| sampler.sample_with_state(&mut state, &[], &logits, &mut rng))
+            .collect();
+        assert_eq!(sampled, [1, 2, 1]);
+        assert_eq!(sampler.sample(&[], &logits, &mut rng), 1);
+    }
+}
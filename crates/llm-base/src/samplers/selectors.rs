@@ -0,0 +1,27 @@
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+
+use super::{Candidates, TokenSelector};
+use crate::TokenId;
+
+/// Picks a token at random, weighted by the probability of each candidate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleRandom;
+impl TokenSelector for SampleRandom {
+    fn select(&self, candidates: &Candidates, rng: &mut dyn rand::RngCore) -> TokenId {
+        let dist = WeightedIndex::new(candidates.probabilities()).expect("WeightedIndex error");
+        candidates.as_slice()[dist.sample(rng)].id
+    }
+}
+
+/// Picks the candidate with the highest logit. This is deterministic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleGreedy;
+impl TokenSelector for SampleGreedy {
+    fn select(&self, candidates: &Candidates, _rng: &mut dyn rand::RngCore) -> TokenId {
+        candidates
+            .iter()
+            .max_by(|a, b| a.logit.total_cmp(&b.logit))
+            .expect("no candidates to select from")
+            .id
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use super::{Candidate, Candidates, SamplerStage};
+use crate::{TokenBias, TokenId};
+
+/// Overrides the logits of individual tokens.
+///
+/// When a biased token is encountered, the bias will be used instead of its logit.
+/// This is typically applied after the other logit-modifying stages, so that the
+/// bias is used as-is.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Bias {
+    /// The tokens to bias, and the logits to use for them.
+    pub bias_tokens: TokenBias,
+}
+impl SamplerStage for Bias {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        for candidate in candidates.as_mut_slice() {
+            if let Some(logit) = self.bias_tokens.get(candidate.id) {
+                candidate.logit = logit;
+            }
+        }
+    }
+}
+
+/// Penalizes tokens that have been recently generated, using the repetition penalty
+/// from the [CTRL paper](https://arxiv.org/abs/1909.05858).
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RepetitionPenalty {
+    /// The penalty for repeating tokens. Higher values make the generation less
+    /// likely to get into a loop, but may harm results when repetitive outputs
+    /// are desired.
+    pub penalty: f32,
+    /// The number of tokens to consider for the repetition penalty.
+    pub last_n: usize,
+}
+impl SamplerStage for RepetitionPenalty {
+    fn apply(&self, previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        let mut recent_tokens =
+            previous_tokens[previous_tokens.len().saturating_sub(self.last_n)..].to_vec();
+        recent_tokens.sort_unstable();
+        recent_tokens.dedup();
+
+        for candidate in candidates.as_mut_slice() {
+            if recent_tokens.binary_search(&candidate.id).is_ok() {
+                // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
+
+                // if score < 0 then repetition penalty has to multiplied to reduce the previous token probability
+                if candidate.logit < 0.0 {
+                    candidate.logit *= self.penalty;
+                } else {
+                    candidate.logit /= self.penalty;
+                }
+            }
+        }
+    }
+}
+
+/// Penalizes tokens that have been recently generated, in proportion to how often they
+/// have occurred, like the frequency and presence penalties of the OpenAI API.
+///
+/// The logit of each token is reduced by `frequency_penalty` for every time it occurs,
+/// and by `presence_penalty` once if it occurs at all. Penalties of 0.0 disable this stage.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FrequencyPresencePenalty {
+    /// The penalty for each time a token has occurred.
+    pub frequency_penalty: f32,
+    /// The penalty for a token that has occurred at least once.
+    pub presence_penalty: f32,
+    /// The number of tokens to consider for the penalties.
+    pub last_n: usize,
+}
+impl SamplerStage for FrequencyPresencePenalty {
+    fn apply(&self, previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.frequency_penalty == 0.0 && self.presence_penalty == 0.0 {
+            return;
+        }
+
+        let mut counts: HashMap<TokenId, usize> = HashMap::new();
+        for &token in &previous_tokens[previous_tokens.len().saturating_sub(self.last_n)..] {
+            *counts.entry(token).or_default() += 1;
+        }
+
+        let penalty = |candidate: &Candidate| match counts.get(&candidate.id) {
+            Some(&count) => count as f32 * self.frequency_penalty + self.presence_penalty,
+            None => 0.0,
+        };
+        // An infinite penalty rules out a token, but must not rule out every token.
+        if !candidates
+            .iter()
+            .any(|c| c.logit - penalty(c) > f32::NEG_INFINITY)
+        {
+            return;
+        }
+
+        for candidate in candidates.as_mut_slice() {
+            candidate.logit -= penalty(candidate);
+        }
+    }
+}
+
+/// Removes every token that would repeat an n-gram of `size` tokens that has already
+/// occurred in the previous tokens.
+///
+/// A `size` of 0 disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NoRepeatNgram {
+    /// The number of tokens in the n-grams that must not be repeated.
+    pub size: usize,
+}
+impl SamplerStage for NoRepeatNgram {
+    fn apply(&self, previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.size == 0 || previous_tokens.len() < self.size {
+            return;
+        }
+
+        // The next token would complete an n-gram that starts with the last `size - 1`
+        // tokens, so it must not be any token that followed them before.
+        let prefix = &previous_tokens[previous_tokens.len() + 1 - self.size..];
+        let mut banned: Vec<TokenId> = previous_tokens
+            .windows(self.size)
+            .filter(|ngram| &ngram[..self.size - 1] == prefix)
+            .map(|ngram| ngram[self.size - 1])
+            .collect();
+        banned.sort_unstable();
+        banned.dedup();
+
+        // If every token that can be picked would repeat an n-gram, such as when a
+        // constraint only allows one, one of them is repeated rather than removing them all.
+        let is_allowed = |c: &Candidate| banned.binary_search(&c.id).is_err();
+        if !candidates
+            .iter()
+            .any(|c| c.logit > f32::NEG_INFINITY && is_allowed(c))
+        {
+            return;
+        }
+        candidates.retain(is_allowed);
+    }
+}
+
+/// Scales the logits by the inverse of the temperature (randomness). A higher
+/// temperature makes the output more random.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Temperature {
+    /// The temperature to use.
+    pub temperature: f32,
+}
+impl SamplerStage for Temperature {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        let scale = 1.0 / self.temperature;
+        for candidate in candidates.as_mut_slice() {
+            candidate.logit *= scale;
+        }
+    }
+}
+
+/// Top-K: only the `k` tokens with the highest logits are kept.
+///
+/// A `k` of 0 disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TopK {
+    /// The number of tokens to keep.
+    pub k: usize,
+}
+impl SamplerStage for TopK {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.k > 0 {
+            candidates.keep_top(self.k);
+        }
+    }
+}
+
+/// Top-P (nucleus sampling): only the most likely tokens whose cumulative probability
+/// reaches `p` are kept.
+///
+/// A `p` of 1.0 or more disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TopP {
+    /// The cumulative probability after which no more tokens are kept.
+    pub p: f32,
+}
+impl SamplerStage for TopP {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.p >= 1.0 {
+            return;
+        }
+
+        candidates.sort();
+        let probs = candidates.probabilities();
+
+        let mut cumsum = 0.0;
+        for (i, p) in probs.iter().enumerate() {
+            cumsum += p;
+            if cumsum >= self.p {
+                candidates.truncate(i + 1);
+                break;
+            }
+        }
+    }
+}
+
+/// Min-P: only the tokens whose probability is at least `p` times the probability
+/// of the most likely token are kept.
+///
+/// Unlike [TopP], the cutoff scales with the confidence of the model. A `p` of 0.0
+/// disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MinP {
+    /// The minimum probability of a token, relative to the most likely token.
+    pub p: f32,
+}
+impl SamplerStage for MinP {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.p <= 0.0 || candidates.is_empty() {
+            return;
+        }
+
+        // p_i >= p * p_max is equivalent to logit_i >= logit_max + ln(p), which
+        // avoids computing the probabilities.
+        let max_logit = candidates
+            .iter()
+            .map(|c| c.logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let min_logit = max_logit + self.p.ln();
+        candidates.retain(|c| c.logit >= min_logit);
+    }
+}
+
+/// [Tail-free sampling](https://www.trentonbricken.com/Tail-Free-Sampling/): removes
+/// the "tail" of unlikely tokens, found where the second derivative of the sorted
+/// probabilities flattens out.
+///
+/// A `z` of 1.0 or more disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TailFree {
+    /// The cumulative weight of the second derivatives after which no more tokens are
+    /// kept. Lower values remove more of the tail.
+    pub z: f32,
+}
+impl SamplerStage for TailFree {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.z >= 1.0 || candidates.len() <= 2 {
+            return;
+        }
+
+        candidates.sort();
+        let probs = candidates.probabilities();
+
+        let first_derivatives: Vec<f32> = probs.windows(2).map(|w| w[0] - w[1]).collect();
+        let mut second_derivatives: Vec<f32> = first_derivatives
+            .windows(2)
+            .map(|w| (w[0] - w[1]).abs())
+            .collect();
+        let sum: f32 = second_derivatives.iter().sum();
+        if sum <= 0.0 {
+            // The distribution is linear; there is no tail to find.
+            return;
+        }
+        for d in second_derivatives.iter_mut() {
+            *d /= sum;
+        }
+
+        let mut cumsum = 0.0;
+        let mut keep = candidates.len();
+        for (i, d) in second_derivatives.iter().enumerate() {
+            cumsum += d;
+            if cumsum > self.z {
+                keep = i;
+                break;
+            }
+        }
+        candidates.truncate(keep.max(1));
+    }
+}
+
+/// [Locally typical sampling](https://arxiv.org/abs/2202.00666): keeps the tokens
+/// whose surprise is closest to the expected surprise (entropy) of the distribution,
+/// until their cumulative probability reaches `p`.
+///
+/// A `p` of 1.0 or more disables this stage.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LocallyTypical {
+    /// The cumulative probability after which no more tokens are kept.
+    pub p: f32,
+}
+impl SamplerStage for LocallyTypical {
+    fn apply(&self, _previous_tokens: &[TokenId], candidates: &mut Candidates) {
+        if self.p >= 1.0 || candidates.is_empty() {
+            return;
+        }
+
+        let probs = candidates.probabilities();
+        let entropy: f32 = probs
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|&p| -p * p.ln())
+            .sum();
+
+        // Order the tokens by how far their surprise is from the entropy.
+        let distances: Vec<f32> = probs.iter().map(|&p| (-p.ln() - entropy).abs()).collect();
+        let mut order: Vec<usize> = (0..probs.len()).collect();
+        order.sort_by(|&a, &b| distances[a].total_cmp(&distances[b]));
+
+        let mut keep = vec![false; probs.len()];
+        let mut cumsum = 0.0;
+        for &i in &order {
+            keep[i] = true;
+            cumsum += probs[i];
+            if cumsum >= self.p {
+                break;
+            }
+        }
+
+        // Retaining preserves the existing order of the candidates.
+        let mut kept = keep.into_iter();
+        candidates.retain(|_| kept.next().unwrap_or(false));
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use rand::SeedableRng;
+use tracing::log;
+
+use crate::{
+    BatchSequence, InferenceError, InferenceParameters, InferenceSession, InferenceSessionConfig,
+    Model, OutputRequest, Prompt, TokenId, TokenUtf8Buffer,
+};
+
+/// A request to generate text, submitted to a [Scheduler].
+#[derive(Clone, Debug)]
+pub struct ScheduledRequest {
+    /// The prompt to feed to a new session before generating.
+    pub prompt: String,
+    /// The parameters to generate with.
+    pub parameters: InferenceParameters,
+    /// The maximum number of tokens to generate, or unlimited if `None`.
+    pub maximum_token_count: Option<usize>,
+    /// The seed of the random number generator used for sampling.
+    pub seed: u64,
+}
+
+/// Configuration for a [Scheduler].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// The configuration of the session of each request.
+    ///
+    /// Up to [InferenceSessionConfig::n_batch] tokens of each prompt are fed in each
+    /// step, and the number of threads of the first session in a step is used to
+    /// evaluate it.
+    pub session_config: InferenceSessionConfig,
+    /// The maximum number of requests that are evaluated together. Any other requests
+    /// wait until one of them is finished.
+    ///
+    /// This is not limited by the size of a graph, as models evaluate larger batches
+    /// in several graphs.
+    pub max_sequences: usize,
+}
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            session_config: Default::default(),
+            max_sequences: 8,
+        }
+    }
+}
+
+/// Generates text for requests from any number of threads with one shared model, by
+/// evaluating all of the requests that are in progress together with
+/// [Model::evaluate_batch].
+///
+/// Each step feeds the next part of the prompt of the requests that are still being
+/// ingested and the last generated token of the others, so new requests don't wait for
+/// the others to finish. The generated text of each request is streamed through the
+/// [ScheduledGeneration] returned by [Self::submit].
+///
+/// ```
+/// # fn example(model: std::sync::Arc<dyn llm_base::Model>) -> Result<(), llm_base::InferenceError> {
+/// use llm_base::{ScheduledRequest, Scheduler};
+///
+/// let scheduler = Scheduler::new(model, Default::default());
+/// let generation = scheduler.submit(ScheduledRequest {
+///     prompt: "Rust is a cool programming language because".to_string(),
+///     parameters: Default::default(),
+///     maximum_token_count: Some(64),
+///     seed: 0,
+/// });
+/// for text in generation {
+///     print!("{}", text?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Scheduler {
+    sender: Option<Mutex<mpsc::Sender<Job>>>,
+    worker: Option<JoinHandle<()>>,
+}
+impl Scheduler {
+    /// Starts a scheduler for `model`, which evaluates the requests on its own thread
+    /// until the scheduler is dropped. Dropping the scheduler ends the generation of the
+    /// requests that are still in progress.
+    pub fn new(model: Arc<dyn Model>, config: SchedulerConfig) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            Worker {
+                model,
+                config,
+                receiver,
+                active: vec![],
+                idle_sessions: vec![],
+            }
+            .run()
+        });
+        Self {
+            sender: Some(Mutex::new(sender)),
+            worker: Some(worker),
+        }
+    }
+
+    /// Adds `request` to the requests that are being evaluated, and returns the stream
+    /// of its generated text.
+    ///
+    /// Dropping the returned [ScheduledGeneration] cancels the request.
+    pub fn submit(&self, request: ScheduledRequest) -> ScheduledGeneration {
+        let (job, generation) = Job::new(request);
+        // The worker only stops once the scheduler is dropped.
+        let _ = self
+            .sender
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(job);
+        generation
+    }
+}
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        // Disconnect the worker, which stops it after the step that it is evaluating.
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The text generated for a request submitted to a [Scheduler].
+///
+/// This iterates over the text of each generated token as it becomes available, and
+/// ends when generation is finished. If generation fails, the error is the last item.
+pub struct ScheduledGeneration {
+    receiver: mpsc::Receiver<Result<String, InferenceError>>,
+    cancelled: Arc<AtomicBool>,
+}
+impl Iterator for ScheduledGeneration {
+    type Item = Result<String, InferenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+impl Drop for ScheduledGeneration {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Worker {
+    model: Arc<dyn Model>,
+    config: SchedulerConfig,
+    receiver: mpsc::Receiver<Job>,
+    active: Vec<Job>,
+    // Sessions of finished requests, which are reused rather than allocated again.
+    idle_sessions: Vec<InferenceSession>,
+}
+impl Worker {
+    fn run(mut self) {
+        loop {
+            if self.active.is_empty() {
+                match self.receiver.recv() {
+                    Ok(job) => self.active.push(job),
+                    Err(_) => return,
+                }
+            }
+            while self.active.len() < self.config.max_sequences.max(1) {
+                match self.receiver.try_recv() {
+                    Ok(job) => self.active.push(job),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+
+            self.step();
+        }
+    }
+
+    /// Evaluates the next input of each active request, and removes the finished ones.
+    fn step(&mut self) {
+        let model = self.model.as_ref();
+        let inputs: Vec<_> = self
+            .active
+            .iter_mut()
+            .map(|job| {
+                let input = job
+                    .start(model, &mut self.idle_sessions, self.config.session_config)
+                    .and_then(|()| job.next_input(model));
+                input.unwrap_or_else(|e| {
+                    let _ = job.sender.send(Err(e));
+                    None
+                })
+            })
+            .collect();
+
+        let mut output_requests = vec![OutputRequest::default(); inputs.len()];
+        let mut batch: Vec<_> = self
+            .active
+            .iter_mut()
+            .zip(&inputs)
+            .zip(&mut output_requests)
+            .filter_map(|((job, input), output_request)| {
+                Some(BatchSequence {
+                    session: job.session.as_mut()?,
+                    input_tokens: input.as_ref()?,
+                    output_request,
+                })
+            })
+            .collect();
+        if !batch.is_empty() {
+            log::trace!("Evaluating a batch of {} sequences", batch.len());
+            model.evaluate_batch(&mut batch);
+        }
+
+        for index in (0..inputs.len()).rev() {
+            if inputs[index].is_none() {
+                let job = self.active.remove(index);
+                if let Some(mut session) = job.session {
+                    session.clear();
+                    self.idle_sessions.push(session);
+                }
+            }
+        }
+    }
+}
+
+/// A request that is being evaluated by a [Worker].
+struct Job {
+    request: ScheduledRequest,
+    session: Option<InferenceSession>,
+    rng: rand::rngs::StdRng,
+    prompt: Vec<TokenId>,
+    prompt_fed: usize,
+    generated: usize,
+    utf8: TokenUtf8Buffer,
+    sender: mpsc::Sender<Result<String, InferenceError>>,
+    cancelled: Arc<AtomicBool>,
+}
+impl Job {
+    fn new(request: ScheduledRequest) -> (Self, ScheduledGeneration) {
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let job = Job {
+            rng: rand::rngs::StdRng::seed_from_u64(request.seed),
+            request,
+            session: None,
+            prompt: vec![],
+            prompt_fed: 0,
+            generated: 0,
+            utf8: TokenUtf8Buffer::new(),
+            sender,
+            cancelled: cancelled.clone(),
+        };
+        (
+            job,
+            ScheduledGeneration {
+                receiver,
+                cancelled,
+            },
+        )
+    }
+
+    /// Starts the session of this request and tokenizes its prompt, if that wasn't done
+    /// already.
+    fn start(
+        &mut self,
+        model: &dyn Model,
+        idle_sessions: &mut Vec<InferenceSession>,
+        session_config: InferenceSessionConfig,
+    ) -> Result<(), InferenceError> {
+        if self.session.is_some() || self.cancelled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.session = Some(
+            idle_sessions
+                .pop()
+                .unwrap_or_else(|| model.start_session(session_config)),
+        );
+        self.prompt = Prompt::from(&self.request.prompt).to_tokens(model.tokenizer(), true)?;
+        Ok(())
+    }
+
+    /// Returns the tokens to evaluate next, or `None` if this request is finished.
+    fn next_input(&mut self, model: &dyn Model) -> Result<Option<Vec<TokenId>>, InferenceError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let session = self.session.as_mut().unwrap();
+
+        if self.prompt_fed < self.prompt.len() {
+            let end = (self.prompt_fed + session.config.n_batch).min(self.prompt.len());
+            let chunk = self.prompt[self.prompt_fed..end].to_vec();
+            session.make_room(model, chunk.len())?;
+            for &token in &chunk {
+                session.push_token(model, token);
+            }
+            self.prompt_fed = end;
+            return Ok(Some(chunk));
+        }
+
+        if self
+            .request
+            .maximum_token_count
+            .map_or(false, |max| self.generated >= max)
+        {
+            return Ok(None);
+        }
+
+        session.make_room(model, 1)?;
+        let token = session.sampler_state.sample(
+            &self.request.parameters.sampler,
+            &session.tokens,
+            &session.last_logits,
+            &mut self.rng,
+        );
+        let bytes = session.push_token(model, token);
+        self.generated += 1;
+        if token == model.eot_token_id() {
+            return Ok(None);
+        }
+
+        if let Some(text) = self.utf8.push(&bytes) {
+            if self.sender.send(Ok(text)).is_err() {
+                // The request was cancelled.
+                return Ok(None);
+            }
+        }
+        Ok(Some(vec![token]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_model::MockModel, samplers::SampleGreedy, samplers::SamplerChain};
+
+    fn worker(model: &Arc<MockModel>, config: SchedulerConfig) -> Worker {
+        Worker {
+            model: model.clone(),
+            config,
+            receiver: mpsc::channel().1,
+            active: vec![],
+            idle_sessions: vec![],
+        }
+    }
+
+    fn submit(
+        worker: &mut Worker,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> ScheduledGeneration {
+        let (job, generation) = Job::new(ScheduledRequest {
+            prompt: prompt.to_string(),
+            parameters: InferenceParameters {
+                sampler: Arc::new(SamplerChain::new(SampleGreedy)),
+            },
+            maximum_token_count,
+            seed: 0,
+        });
+        worker.active.push(job);
+        generation
+    }
+
+    fn run(worker: &mut Worker) {
+        while !worker.active.is_empty() {
+            worker.step();
+        }
+    }
+
+    #[test]
+    fn test_scheduler_interleaves_requests() {
+        let model = Arc::new(MockModel::new(32));
+        let mut worker = worker(&model, Default::default());
+
+        let first = submit(&mut worker, "ab", Some(3));
+        let second = submit(&mut worker, "w", None);
+        run(&mut worker);
+
+        assert_eq!(first.map(Result::unwrap).collect::<String>(), "cde");
+        // The second request ends with the end of text after `z`.
+        assert_eq!(second.map(Result::unwrap).collect::<String>(), "xyz");
+        assert_eq!(*model.batch_sizes.lock().unwrap(), [2, 2, 2, 2]);
+
+        // The sessions of finished requests are cleared and used again.
+        assert_eq!(worker.idle_sessions.len(), 2);
+        let third = submit(&mut worker, "q", Some(2));
+        run(&mut worker);
+        assert_eq!(third.map(Result::unwrap).collect::<String>(), "rs");
+        assert_eq!(worker.idle_sessions.len(), 2);
+    }
+
+    #[test]
+    fn test_scheduler_cancels_dropped_requests() {
+        let model = Arc::new(MockModel::new(32));
+        let config = SchedulerConfig {
+            session_config: InferenceSessionConfig {
+                n_batch: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut worker = worker(&model, config);
+
+        // A request that is dropped before its prompt is fed is never evaluated.
+        drop(submit(&mut worker, "abcdefgh", None));
+        worker.step();
+        assert!(worker.active.is_empty());
+        assert!(model.batch_sizes.lock().unwrap().is_empty());
+
+        // A request that is dropped while generating ends at the next step.
+        let mut generation = submit(&mut worker, "a", None);
+        worker.step();
+        worker.step();
+        assert_eq!(generation.next().unwrap().unwrap(), "b");
+        drop(generation);
+        worker.step();
+        assert!(worker.active.is_empty());
+        assert_eq!(*model.batch_sizes.lock().unwrap(), [1, 1]);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{
+    inference_session::GeneratedToken, InferenceError, InferenceFeedback, InferenceParameters,
+    InferenceRequest, InferenceResponse, InferenceSession, InferenceSessionConfig, InferenceStats,
+    Model, OutputRequest, RewindError, TokenId,
+};
+
+/// Proposes the tokens that a model is likely to generate next, so that they can be
+/// checked together by [InferenceSession::infer_speculative].
+pub trait Drafter {
+    /// Proposes the tokens that are likely to follow `tokens`, which are the tokens of
+    /// the session that is generating text.
+    ///
+    /// The proposal may be empty, and is shortened if it is too long to be checked.
+    fn draft(&mut self, tokens: &[TokenId]) -> Result<Vec<TokenId>, InferenceError>;
+}
+
+/// A [Drafter] that proposes tokens by generating them with a smaller model.
+///
+/// The draft model must use the same tokenizer as the model that is generating, and
+/// must [support rewinding](Model::supports_rewind). Its tokens are picked greedily.
+pub struct DraftModel<'a> {
+    model: &'a dyn Model,
+    session: InferenceSession,
+    /// The number of tokens to propose at a time.
+    pub draft_length: usize,
+}
+impl<'a> DraftModel<'a> {
+    /// Creates a drafter that proposes `draft_length` tokens at a time with `model`,
+    /// in a session with the given `config`.
+    pub fn new(model: &'a dyn Model, config: InferenceSessionConfig, draft_length: usize) -> Self {
+        Self {
+            model,
+            session: model.start_session(config),
+            draft_length,
+        }
+    }
+}
+impl Drafter for DraftModel<'_> {
+    fn draft(&mut self, tokens: &[TokenId]) -> Result<Vec<TokenId>, InferenceError> {
+        let draft_length = self
+            .draft_length
+            .min(self.model.context_size().saturating_sub(tokens.len() + 1));
+        if tokens.is_empty() || draft_length == 0 {
+            return Ok(vec![]);
+        }
+
+        // The session still has the previous draft, which shares a prefix with `tokens`.
+        self.session
+            .sync_tokens(self.model, tokens, &mut OutputRequest::default())?;
+
+        let mut draft = vec![];
+        for _ in 0..draft_length {
+            let token = self
+                .session
+                .last_logits
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(id, _)| id as TokenId)
+                .unwrap_or_else(|| self.model.eot_token_id());
+            if token == self.model.eot_token_id() {
+                break;
+            }
+
+            draft.push(token);
+            self.session.feed_tokens(
+                self.model,
+                &[token],
+                &mut OutputRequest::default(),
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )?;
+        }
+        Ok(draft)
+    }
+}
+
+/// A [Drafter] that proposes the tokens that followed the last occurrence of the end of
+/// the session's tokens, which works well when the output copies from the prompt, such
+/// as for extraction or editing. No other model is needed.
+///
+/// The longest n-gram at the end of the tokens, of up to [Self::ngram_size] tokens, that
+/// also appears earlier is looked up, and the tokens after it are proposed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptLookup {
+    /// The maximum number of tokens to match.
+    pub ngram_size: usize,
+    /// The maximum number of tokens to propose at a time.
+    pub draft_length: usize,
+}
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self {
+            ngram_size: 3,
+            draft_length: 8,
+        }
+    }
+}
+impl Drafter for PromptLookup {
+    fn draft(&mut self, tokens: &[TokenId]) -> Result<Vec<TokenId>, InferenceError> {
+        for n in (1..=self.ngram_size.min(tokens.len().saturating_sub(1))).rev() {
+            let ngram = &tokens[tokens.len() - n..];
+            // Prefer the most recent match, as it is the most likely to be relevant.
+            if let Some(start) = tokens[..tokens.len() - 1]
+                .windows(n)
+                .rposition(|window| window == ngram)
+            {
+                let continuation = &tokens[start + n..];
+                return Ok(continuation[..continuation.len().min(self.draft_length)].to_vec());
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+impl InferenceSession {
+    /// Generate text like [Self::infer], using speculative decoding to generate several
+    /// tokens for each evaluation of the `model`.
+    ///
+    /// At each step, the `drafter` proposes the next tokens, with a smaller [DraftModel]
+    /// or from the session's own tokens with [PromptLookup]. They are evaluated together,
+    /// and the sampler picks each token from the logits for its position: the proposed
+    /// tokens are kept for as long as they are the ones that it picks. The first token
+    /// that differs replaces the rest of the proposal, which is rewound. As every token is
+    /// still picked by the sampler, the output is the same as that of [Self::infer], but
+    /// faster when the proposals are good.
+    ///
+    /// The `model` must [support rewinding](Model::supports_rewind), and the proposals
+    /// are limited to [InferenceSessionConfig::n_batch] tokens.
+    pub fn infer_speculative<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        drafter: &mut dyn Drafter,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
+        callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
+    ) -> Result<InferenceStats, InferenceError> {
+        if !model.supports_rewind() {
+            return Err(RewindError::UnsupportedArchitecture.into());
+        }
+        self.infer_with_drafter(model, Some(drafter), rng, request, output_request, callback)
+    }
+
+    /// Generates up to `limit` tokens by checking the tokens proposed by `drafter` in
+    /// a single evaluation.
+    pub(crate) fn infer_next_tokens_speculative(
+        &mut self,
+        model: &dyn Model,
+        drafter: &mut dyn Drafter,
+        params: &InferenceParameters,
+        limit: usize,
+        keep_logits: bool,
+        rng: &mut impl rand::Rng,
+    ) -> Result<Vec<GeneratedToken>, InferenceError> {
+        let eot = model.eot_token_id();
+        let mut draft = drafter.draft(&self.tokens)?;
+        if let Some(end) = draft.iter().position(|&t| t == eot) {
+            draft.truncate(end);
+        }
+        // One more token is always generated after the accepted part of the draft, and
+        // it must fit in the context window too.
+        draft.truncate(
+            limit
+                .saturating_sub(1)
+                .min(self.config.n_batch)
+                .min(model.context_size().saturating_sub(self.n_past + 2)),
+        );
+        if draft.is_empty() {
+            return Ok(vec![self.generate_next_token(
+                model,
+                params,
+                keep_logits,
+                rng,
+            )?]);
+        }
+
+        // Evaluate the draft, keeping the logits for each of its positions. The logits
+        // before its first token are the ones that the session already has.
+        let start = self.tokens.len();
+        let previous_logits = self.last_logits.clone();
+        let mut bytes: Vec<_> = draft.iter().map(|&t| self.push_token(model, t)).collect();
+        let mut output_request = OutputRequest {
+            all_logits: Some(vec![]),
+            embeddings: None,
+        };
+        model.evaluate(self, &draft, &mut output_request);
+        let all_logits = output_request.all_logits.unwrap_or_default();
+        let n_vocab = previous_logits.len();
+
+        let mut generated = vec![];
+        for i in 0..=draft.len() {
+            let logits = match i {
+                0 => &previous_logits[..],
+                i => &all_logits[(i - 1) * n_vocab..i * n_vocab],
+            };
+            let token =
+                self.sampler_state
+                    .sample(&params.sampler, &self.tokens[..start + i], logits, rng);
+            let logits = keep_logits.then(|| logits.to_vec());
+
+            if i < draft.len() && token == draft[i] {
+                generated.push(GeneratedToken {
+                    id: token,
+                    bytes: std::mem::take(&mut bytes[i]),
+                    logits,
+                });
+                continue;
+            }
+
+            // The sampler picked a different token, or the whole draft was accepted.
+            // Either way, the picked token replaces the rest of the draft.
+            if i < draft.len() {
+                self.rewind(model, draft.len() - i)?;
+            }
+            let text = if token == eot {
+                self.tokens.push(token);
+                vec![]
+            } else {
+                self.push_token(model, token)
+            };
+            model.evaluate(self, &[token], &mut OutputRequest::default());
+            generated.push(GeneratedToken {
+                id: token,
+                bytes: text,
+                logits,
+            });
+            break;
+        }
+        Ok(generated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        mock_model::MockModel,
+        samplers::{SampleGreedy, SamplerChain},
+    };
+
+    #[test]
+    fn test_draft_model_with_empty_control_tokens() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let mut drafter = DraftModel::new(&model, Default::default(), 3);
+
+        // The first draft evaluates the whole context, starting with the empty `<s>`.
+        assert_eq!(drafter.draft(&[1, 3]).unwrap(), [4, 5, 6]);
+        assert_eq!(drafter.draft(&[1, 3, 4, 7]).unwrap(), [8, 9, 10]);
+        // The draft stops before the end of text.
+        assert_eq!(drafter.draft(&[1, 27]).unwrap(), [28]);
+    }
+
+    #[test]
+    fn test_infer_speculative_with_draft_model() {
+        let model = MockModel::with_empty_control_tokens(32);
+        let draft_model = MockModel::with_empty_control_tokens(32);
+        let mut drafter = DraftModel::new(&draft_model, Default::default(), 4);
+        let mut session = model.start_session(Default::default());
+        let parameters = InferenceParameters {
+            sampler: Arc::new(SamplerChain::new(SampleGreedy)),
+        };
+
+        let mut text = String::new();
+        session
+            .infer_speculative(
+                &model,
+                &mut drafter,
+                &mut rand::thread_rng(),
+                &InferenceRequest::new("a".into(), &parameters),
+                &mut Default::default(),
+                |response| {
+                    if let InferenceResponse::InferredToken(token) = response {
+                        text.push_str(&token);
+                    }
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        assert_eq!(text, "bcdefghijklmnopqrstuvwxyz");
+    }
+
+    #[test]
+    fn test_prompt_lookup() {
+        let mut lookup = PromptLookup {
+            ngram_size: 2,
+            draft_length: 3,
+        };
+        // The longest n-gram is preferred over a more recent, shorter one.
+        assert_eq!(
+            lookup.draft(&[1, 2, 3, 4, 5, 6, 9, 2, 8, 1, 2]).unwrap(),
+            [3, 4, 5]
+        );
+        // Of two matches, the most recent is used.
+        assert_eq!(lookup.draft(&[7, 1, 2, 1, 3, 1]).unwrap(), [3, 1]);
+        assert!(lookup.draft(&[1, 2, 3]).unwrap().is_empty());
+        assert!(lookup.draft(&[]).unwrap().is_empty());
+    }
+}
@@ -265,7 +265,7 @@ impl<'a> From<&'a Vec<TokenId>> for Prompt<'a> {
     }
 }
 
-#[derive(Default, Clone, Debug, PartialEq)]
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 /// A list of tokens to bias during the process of inferencing.
 ///
 /// When a biased token is encountered, the bias will be used
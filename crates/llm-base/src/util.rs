@@ -24,7 +24,7 @@ macro_rules! mulf {
 use memmap2::{Mmap, MmapAsRawDesc, MmapOptions};
 use thiserror::Error;
 
-use crate::{FileType, LoadError};
+use crate::{FileType, LoadError, TokenId};
 
 /// Read the filetype from a reader.
 pub fn read_filetype(reader: &mut dyn BufRead) -> Result<FileType, LoadError> {
@@ -73,6 +73,165 @@ impl TokenUtf8Buffer {
     }
 }
 
+/// Holds back generated text that could be the start of a stop sequence, until it is
+/// known whether it is one.
+///
+/// This works on text rather than tokens, so stop sequences are found regardless of
+/// how they were split into tokens.
+pub(crate) struct StopSequenceBuffer<'a> {
+    stop_sequences: &'a [String],
+    held: String,
+}
+impl<'a> StopSequenceBuffer<'a> {
+    pub(crate) fn new(stop_sequences: &'a [String]) -> Self {
+        Self {
+            stop_sequences,
+            held: String::new(),
+        }
+    }
+
+    /// Adds `text` to the buffer. Returns the text that can no longer be part of a stop
+    /// sequence, and whether a stop sequence was found; if it was, the returned text is
+    /// everything before it, and the stop sequence itself is discarded.
+    pub(crate) fn push(&mut self, text: &str) -> (String, bool) {
+        self.held.push_str(text);
+
+        let stop = self
+            .stop_sequences
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.held.find(s.as_str()))
+            .min();
+        if let Some(stop) = stop {
+            let mut released = std::mem::take(&mut self.held);
+            released.truncate(stop);
+            return (released, true);
+        }
+
+        // Keep the longest end of the text that could still become a stop sequence.
+        let keep_from = self
+            .held
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                self.stop_sequences
+                    .iter()
+                    .any(|s| s.starts_with(&self.held[i..]))
+            })
+            .unwrap_or(self.held.len());
+        let held = self.held.split_off(keep_from);
+        (std::mem::replace(&mut self.held, held), false)
+    }
+
+    /// Releases the text that is being held back, as generation has ended without
+    /// completing a stop sequence.
+    pub(crate) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// Holds back generated tokens whose text could be part of a banned phrase, until it is
+/// known whether it is one.
+///
+/// Like [StopSequenceBuffer], this works on the text of the tokens, so phrases are found
+/// regardless of how they were split into tokens. Each token is held with an `item` that
+/// is returned when the token is released.
+pub(crate) struct BannedPhraseBuffer<'a, T> {
+    phrases: &'a [String],
+    /// The held tokens, with the length of their text.
+    held: Vec<(TokenId, usize, T)>,
+    text: Vec<u8>,
+}
+
+/// The result of [BannedPhraseBuffer::push].
+pub(crate) enum BannedPhraseOutcome<T> {
+    /// These tokens can no longer be part of a banned phrase.
+    Released(Vec<T>),
+    /// A banned phrase was completed. The last `rewind` tokens that were pushed, which
+    /// start with `token`, contain the phrase and have been discarded.
+    Banned { rewind: usize, token: TokenId },
+}
+
+impl<'a, T> BannedPhraseBuffer<'a, T> {
+    pub(crate) fn new(phrases: &'a [String]) -> Self {
+        Self {
+            phrases,
+            held: vec![],
+            text: vec![],
+        }
+    }
+
+    /// Adds a token to the buffer, with its `item` and the function that reads its text from it.
+    pub(crate) fn push(
+        &mut self,
+        token: TokenId,
+        item: T,
+        text: impl FnOnce(&T) -> &[u8],
+    ) -> BannedPhraseOutcome<T> {
+        let text = text(&item);
+        self.text.extend_from_slice(text);
+        self.held.push((token, text.len(), item));
+
+        let phrases = || {
+            self.phrases
+                .iter()
+                .filter(|p| !p.is_empty())
+                .map(|p| p.as_bytes())
+        };
+        let banned = phrases()
+            .filter_map(|p| self.text.windows(p.len()).position(|w| w == p))
+            .min();
+        if let Some(start) = banned {
+            // Discard the token that the phrase starts in, and everything after it.
+            let mut end = 0;
+            let index = self
+                .held
+                .iter()
+                .position(|(_, len, _)| {
+                    end += len;
+                    end > start
+                })
+                .unwrap_or(self.held.len() - 1);
+            let rewind = self.held.len() - index;
+            let token = self.held[index].0;
+            self.held.truncate(index);
+            self.text
+                .truncate(self.held.iter().map(|(_, len, _)| len).sum());
+            return BannedPhraseOutcome::Banned { rewind, token };
+        }
+
+        // Keep the tokens that overlap with the longest end of the text that could still
+        // become a banned phrase.
+        let keep_from = (0..=self.text.len())
+            .find(|&i| phrases().any(|p| p.starts_with(&self.text[i..])))
+            .unwrap_or(self.text.len());
+        let mut end = 0;
+        let released = self
+            .held
+            .iter()
+            .take_while(|(_, len, _)| {
+                end += len;
+                end <= keep_from
+            })
+            .count();
+        let released_len: usize = self.held[..released].iter().map(|(_, len, _)| len).sum();
+        self.text.drain(..released_len);
+        BannedPhraseOutcome::Released(
+            self.held
+                .drain(..released)
+                .map(|(_, _, item)| item)
+                .collect(),
+        )
+    }
+
+    /// Releases the tokens that are being held back, as generation has ended without
+    /// completing a banned phrase.
+    pub(crate) fn flush(&mut self) -> Vec<T> {
+        self.text.clear();
+        self.held.drain(..).map(|(_, _, item)| item).collect()
+    }
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the loading process.
 pub enum FindAllModelFilesError {
@@ -152,10 +311,71 @@ pub fn softmax(logits: &[f32]) -> Vec<f32> {
     probs
 }
 
+/// Calculate the logarithm of the softmax for a slice.
+///
+/// This is more accurate than taking the logarithm of [softmax], as very small
+/// probabilities do not underflow to zero.
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let log_sum = logits
+        .iter()
+        .map(|v| (v - max_logit).exp())
+        .sum::<f32>()
+        .ln();
+    logits.iter().map(|v| v - max_logit - log_sum).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_stop_sequence_buffer() {
+        let stop_sequences = ["\nUser:".to_string(), "###".to_string()];
+        let mut buffer = StopSequenceBuffer::new(&stop_sequences);
+
+        assert_eq!(buffer.push("Hello"), ("Hello".to_string(), false));
+        assert_eq!(buffer.push(" there\nU"), (" there".to_string(), false));
+        assert_eq!(buffer.push("s"), (String::new(), false));
+        assert_eq!(buffer.push("a"), ("\nUsa".to_string(), false));
+        assert_eq!(buffer.push("é #"), ("é ".to_string(), false));
+        assert_eq!(buffer.flush(), "#");
+
+        assert_eq!(buffer.push("ok##"), ("ok".to_string(), false));
+        assert_eq!(buffer.push("#\nUser:"), (String::new(), true));
+        assert_eq!(buffer.flush(), "");
+    }
+
+    #[test]
+    fn test_banned_phrase_buffer() {
+        let phrases = ["bad word".to_string()];
+        let mut buffer = BannedPhraseBuffer::new(&phrases);
+        let mut push = |token: TokenId, text: &'static str| match buffer
+            .push(token, token, |_| text.as_bytes())
+        {
+            BannedPhraseOutcome::Released(tokens) => Ok(tokens),
+            BannedPhraseOutcome::Banned { rewind, token } => Err((rewind, token)),
+        };
+
+        assert_eq!(push(0, "a"), Ok(vec![0]));
+        assert_eq!(push(1, " ba"), Ok(vec![]));
+        assert_eq!(push(2, "d"), Ok(vec![]));
+        assert_eq!(push(3, " wo"), Ok(vec![]));
+        assert_eq!(push(4, "rd"), Err((4, 1)));
+        assert_eq!(push(5, " big"), Ok(vec![5]));
+        assert_eq!(push(6, " b"), Ok(vec![]));
+        assert_eq!(buffer.flush(), [6]);
+    }
+
+    #[test]
+    fn test_log_softmax_matches_softmax() {
+        let logits = [1.0, -2.0, 0.5, 3.0];
+        for (log_prob, prob) in log_softmax(&logits).into_iter().zip(softmax(&logits)) {
+            assert!((log_prob.exp() - prob).abs() < 1e-6);
+        }
+        assert!(log_softmax(&[0.0, -200.0])[1].is_finite());
+    }
+
     #[test]
     fn test_collect_related_paths() {
         let main_path = PathBuf::from("/models/llama.bin");
@@ -59,12 +59,7 @@ fn main() {
     let res = session.infer::<Infallible>(
         model.as_ref(),
         &mut rand::thread_rng(),
-        &llm::InferenceRequest {
-            prompt: prompt.into(),
-            parameters: &llm::InferenceParameters::default(),
-            play_back_previous_tokens: false,
-            maximum_token_count: None,
-        },
+        &llm::InferenceRequest::new(prompt.into(), &llm::InferenceParameters::default()),
         // OutputRequest
         &mut Default::default(),
         |r| match r {
@@ -87,14 +87,12 @@ fn main() {
                     .infer::<Infallible>(
                         model.as_ref(),
                         &mut rng,
-                        &llm::InferenceRequest {
-                            prompt: format!("{user_name}: {line}\n{character_name}:")
+                        &llm::InferenceRequest::new(
+                            format!("{user_name}: {line}\n{character_name}:")
                                 .as_str()
                                 .into(),
-                            parameters: &inference_parameters,
-                            play_back_previous_tokens: false,
-                            maximum_token_count: None,
-                        },
+                            &inference_parameters,
+                        ),
                         &mut Default::default(),
                         conversation_inference_callback(&format!("{character_name}:"), print_token),
                     )
@@ -40,12 +40,10 @@
 //!     &mut rand::thread_rng(),
 //!     // the prompt to use for text generation, as well as other
 //!     // inference parameters
-//!     &llm::InferenceRequest {
-//!         prompt: "Rust is a cool programming language because".into(),
-//!         parameters: &llm::InferenceParameters::default(),
-//!         play_back_previous_tokens: false,
-//!         maximum_token_count: None,
-//!     },
+//!     &llm::InferenceRequest::new(
+//!         "Rust is a cool programming language because".into(),
+//!         &llm::InferenceParameters::default(),
+//!     ),
 //!     // llm::OutputRequest
 //!     &mut Default::default(),
 //!     // output callback
@@ -78,13 +76,16 @@ use std::{
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
     conversation_inference_callback, feed_prompt_callback, ggml::format as ggml_format, load,
-    load_progress_callback_stdout, quantize, samplers, ElementType, FileType, FileTypeFormat,
-    FormatMagic, Hyperparameters, InferenceError, InferenceFeedback, InferenceParameters,
-    InferenceRequest, InferenceResponse, InferenceSession, InferenceSessionConfig,
-    InferenceSnapshot, InferenceSnapshotRef, InferenceStats, InvalidTokenBias, KnownModel,
-    LoadError, LoadProgress, Loader, Model, ModelKVMemoryType, ModelParameters, OutputRequest,
-    Prompt, QuantizeError, QuantizeProgress, RewindError, Sampler, SnapshotError, TokenBias,
-    TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer, TokenizerSource,
+    load_progress_callback_stdout, quantize, samplers, BatchSequence, BeamHypothesis,
+    BeamSearchParameters, Completion, ContextShift, ContextShiftError, DraftModel, Drafter,
+    ElementType, FileType, FileTypeFormat, FormatMagic, Hyperparameters, InferenceError,
+    InferenceFeedback, InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
+    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
+    InvalidTokenBias, JsonInferenceError, KnownModel, KvMemoryLayout, LoadError, LoadProgress,
+    Loader, Model, ModelKVMemoryType, ModelParameters, OutputRequest, Prompt, PromptLookup,
+    QuantizeError, QuantizeProgress, RewindError, Rope, Sampler, ScheduledGeneration,
+    ScheduledRequest, Scheduler, SchedulerConfig, SnapshotError, TokenBias, TokenId, TokenLogprobs,
+    TokenUtf8Buffer, TokenizationError, Tokenizer, TokenizerSource,
 };
 
 use serde::Serialize;
@@ -8,7 +8,7 @@ use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    KvMemoryLayout, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
 /// The BLOOM model. Ref: [Introducing BLOOM](https://bigscience.huggingface.co/blog/bloom)
@@ -395,6 +395,15 @@ impl KnownModel for Bloom {
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        Some(KvMemoryLayout {
+            n_layer: self.hyperparameters.n_layer,
+            token_width: self.hyperparameters.n_embd,
+            transposed_values: false,
+            rope: None,
+        })
+    }
 }
 
 /// BLOOM [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -13,8 +13,8 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
+    KvMemoryLayout, LoadError, ModelParameters, OutputRequest, Regex, Rope, TokenId, Tokenizer,
 };
 
 /// The Falcon model. Ref: [Technology Innovation Institute](https://huggingface.co/tiiuae)
@@ -350,6 +350,30 @@ impl KnownModel for Falcon {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        let n_embd = self.hyperparameters.n_embd;
+        let head_dim = n_embd / self.hyperparameters.n_head;
+        // The layout assumes that one head of keys and values is stored for each token, which
+        // is the case when the fused query, key and value rows hold a single key and value head.
+        let qkv_rows = self.layers.first()?.query_key_value.get_ne()[1] as usize;
+        let n_head_kv = qkv_rows.checked_sub(n_embd)? / (2 * head_dim);
+        if n_head_kv != 1 {
+            return None;
+        }
+        Some(KvMemoryLayout {
+            n_layer: self.hyperparameters.n_layer,
+            token_width: head_dim,
+            transposed_values: false,
+            rope: Some(Rope {
+                head_dim,
+                n_dims: head_dim,
+                neox: true,
+                freq_base: ggml::ROPE_FREQ_BASE,
+                freq_scale: ggml::ROPE_FREQ_SCALE,
+            }),
+        })
+    }
 }
 
 /// Falcon [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -7,8 +7,9 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
+    KvMemoryLayout, LoadError, ModelParameters, OutputRequest, Regex, Rope, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -317,6 +318,21 @@ impl KnownModel for GptJ {
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        Some(KvMemoryLayout {
+            n_layer: self.hyperparameters.n_layer,
+            token_width: self.hyperparameters.n_embd,
+            transposed_values: true,
+            rope: Some(Rope {
+                head_dim: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_dims: self.hyperparameters.n_rot,
+                neox: false,
+                freq_base: ggml::ROPE_FREQ_BASE,
+                freq_scale: ggml::ROPE_FREQ_SCALE,
+            }),
+        })
+    }
 }
 
 /// GPT-J [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
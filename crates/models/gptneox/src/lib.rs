@@ -8,8 +8,9 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
+    KvMemoryLayout, LoadError, ModelParameters, OutputRequest, Regex, Rope, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -363,6 +364,21 @@ impl KnownModel for GptNeoX {
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        Some(KvMemoryLayout {
+            n_layer: self.hyperparameters.n_layer,
+            token_width: self.hyperparameters.n_embd,
+            transposed_values: true,
+            rope: Some(Rope {
+                head_dim: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_dims: self.hyperparameters.n_rot,
+                neox: true,
+                freq_base: ggml::ROPE_FREQ_BASE,
+                freq_scale: ggml::ROPE_FREQ_SCALE,
+            }),
+        })
+    }
 }
 
 /// GPT-NeoX [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -4,12 +4,19 @@
 use std::{error::Error, sync::Arc};
 
 use llm_base::{
-    ggml::{self},
+    ggml::{self, ComputationGraph},
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, BatchSequence, BuildContext, FileType, GraphOutputs, InferenceSession,
+    InferenceSessionConfig, KnownModel, KvMemoryLayout, LoadError, ModelParameters, OutputRequest,
+    Regex, Rope, SequenceMemory, TensorLoader, TokenId, Tokenizer,
 };
 
+// The number of graph nodes that each layer of [Llama::build_graph] adds, outside of the
+// attention, and the number that the attention adds for each sequence of a batch. These
+// are slight overestimates, and limit the number of sequences in one graph.
+const NODES_PER_LAYER: usize = 20;
+const NODES_PER_SEQUENCE: usize = 24;
+
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
 ///
 /// # Safety
@@ -125,11 +132,111 @@ impl KnownModel for Llama {
         output_request: &mut OutputRequest,
     ) {
         let input_len = input_tokens.len();
-        let session_len = session.n_past;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = self.hyperparameters;
+
+        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+            self.build_graph(builder)
+        });
+
+        // finish evaluation
+        common::read_last_token(session, &outputs.result, n_vocab, input_len);
+        common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
+        common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn evaluate_batch(&self, batch: &mut [BatchSequence]) {
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = self.hyperparameters;
+
+        // Each graph holds a limited number of nodes, so large batches are split.
+        let max_sequences = common::max_batch_sequences(
+            self.hyperparameters.n_layer,
+            NODES_PER_LAYER,
+            NODES_PER_SEQUENCE,
+        );
+        common::evaluate_in_parts(
+            batch,
+            max_sequences,
+            |sequence| {
+                self.evaluate(
+                    sequence.session,
+                    sequence.input_tokens,
+                    sequence.output_request,
+                )
+            },
+            |batch| {
+                let outputs =
+                    InferenceSession::compute_batch(batch, self.context.clone(), |builder| {
+                        self.build_graph(builder)
+                    });
+
+                common::read_batch_outputs(batch, &outputs, n_vocab, n_embd);
+            },
+        );
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn context_size(&self) -> usize {
+        self.params.context_size
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        None
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        self.tokenizer.id("</s>".as_bytes()).unwrap_or(2)
+    }
+
+    fn quantize_tensors() -> Vec<Regex> {
+        vec![Regex::new(".*weight").unwrap()]
+    }
+
+    fn skip_quantize_tensors() -> Vec<Regex> {
+        vec![]
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
+
+    fn kv_memory_layout(&self) -> Option<KvMemoryLayout> {
+        Some(KvMemoryLayout {
+            n_layer: self.hyperparameters.n_layer,
+            token_width: self.hyperparameters.n_embd,
+            transposed_values: true,
+            rope: Some(Rope {
+                head_dim: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+                n_dims: self.hyperparameters.n_rot,
+                neox: false,
+                freq_base: ggml::ROPE_FREQ_BASE,
+                freq_scale: ggml::ROPE_FREQ_SCALE,
+            }),
+        })
+    }
+}
+
+impl Llama {
+    /// Builds the graph that evaluates the input tokens of each of the sequences of the
+    /// `builder`. The attention of each sequence is computed with its own memory and
+    /// positions, and everything else is computed for all of the tokens at once.
+    fn build_graph(&self, builder: BuildContext) -> (ComputationGraph, GraphOutputs) {
+        let input_len = builder.embd.nelements();
         let ctx_size = self.params.context_size;
 
         let Hyperparameters {
-            n_vocab,
+            n_vocab: _,
             n_embd,
             n_mult: _,
             n_head,
@@ -138,77 +245,113 @@ impl KnownModel for Llama {
             file_type: _,
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
-            let mut ctx0 = builder.ctx0.borrow_mut();
-            let embd = builder.embd;
+        let mut ctx0 = builder.ctx0.borrow_mut();
+        let embd = builder.embd;
+        // With a single sequence, its tensors are used as they are, rather than through views.
+        let single_sequence = builder.sequences.len() == 1;
 
-            let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
+        let mut input_layer = ctx0.op_get_rows(&self.wte, embd);
 
-            let mut gf = ggml::ComputationGraph::new();
+        let mut gf = ggml::ComputationGraph::new();
 
-            for il in 0..n_layer {
-                ctx0.set_offloading(self.params.should_offload(il));
+        for il in 0..n_layer {
+            ctx0.set_offloading(self.params.should_offload(il));
 
-                let input_self_attention = input_layer.share();
-                let mut current: ggml::Tensor;
+            let input_self_attention = input_layer.share();
+            let mut current: ggml::Tensor;
 
-                ctx0.use_scratch(builder.get_scratch(0));
-
-                // norm
-                current = ctx0.op_rms_norm(&input_layer);
-
-                // cur = attention_norm * cur
-                current = ctx0.op_mul(&current, &self.layers[il].attention_norm);
+            ctx0.use_scratch(builder.get_scratch(0));
 
-                // self-attention
-                // compute Q and K and RoPE them
-                let q_current = ctx0
-                    .op_rope_inplace(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wq, &current),
-                            n_embd / n_head,
-                            n_head,
-                            input_len,
+            // norm
+            current = ctx0.op_rms_norm(&input_layer);
+
+            // cur = attention_norm * cur
+            current = ctx0.op_mul(&current, &self.layers[il].attention_norm);
+
+            // self-attention
+            // compute Q, K and V for all of the tokens
+            let q_all = ctx0.op_reshape_3d(
+                &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                n_embd / n_head,
+                n_head,
+                input_len,
+            );
+            let k_all = ctx0.op_reshape_3d(
+                &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                n_embd / n_head,
+                n_head,
+                input_len,
+            );
+            let v_all = ctx0.op_reshape_2d(
+                &ctx0.op_mul_mat(&self.layers[il].wv, &current),
+                n_embd,
+                input_len,
+            );
+
+            // the attention of each sequence is copied into its rows of this
+            let k_q_v_batch = (!single_sequence)
+                .then(|| ctx0.new_tensor_2d(ggml::Type::F32, n_embd, input_len))
+                .map(|t| t.set_name("KQV_batch"));
+            let mut k_q_v_single = None;
+
+            for sequence in &builder.sequences {
+                let SequenceMemory {
+                    memory_k,
+                    memory_v,
+                    n_past: session_len,
+                    input_offset,
+                    input_len: sequence_len,
+                } = *sequence;
+
+                let (q_sequence, k_sequence, v_sequence) = if single_sequence {
+                    (q_all.share(), k_all.share(), v_all.share())
+                } else {
+                    let view_3d = |t: &ggml::Tensor| {
+                        let nb = t.get_nb();
+                        ctx0.op_view_3d(
+                            t,
+                            (n_embd / n_head, n_head, sequence_len),
+                            (nb[1], nb[2]),
+                            input_offset * nb[2],
+                        )
+                    };
+                    let v_nb = v_all.get_nb();
+                    (
+                        view_3d(&q_all),
+                        view_3d(&k_all),
+                        ctx0.op_view_2d(
+                            &v_all,
+                            (n_embd, sequence_len),
+                            v_nb[1],
+                            input_offset * v_nb[1],
                         ),
-                        session_len,
-                        n_rot,
-                        0,
                     )
+                };
+
+                // RoPE Q and K at the positions of this sequence
+                let q_current = ctx0
+                    .op_rope_inplace(&q_sequence, session_len, n_rot, 0)
                     .set_name("Qcur");
                 let k_current = ctx0
-                    .op_rope_inplace(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wk, &current),
-                            n_embd / n_head,
-                            n_head,
-                            input_len,
-                        ),
-                        session_len,
-                        n_rot,
-                        0,
-                    )
+                    .op_rope_inplace(&k_sequence, session_len, n_rot, 0)
                     .set_name("Kcur");
 
                 // store key and value to memory
                 // compute the transposed [N, n_embd] V matrix
-                let v_current = ctx0.op_transpose(&ctx0.op_reshape_2d(
-                    &ctx0.op_mul_mat(&self.layers[il].wv, &current),
-                    n_embd,
-                    input_len,
-                ));
+                let v_current = ctx0.op_transpose(&v_sequence);
 
                 let k = ctx0.op_view_1d(
-                    builder.memory_k,
-                    input_len * n_embd,
-                    (builder.memory_k.element_size() * n_embd) * (il * ctx_size + session_len),
+                    memory_k,
+                    sequence_len * n_embd,
+                    (memory_k.element_size() * n_embd) * (il * ctx_size + session_len),
                 );
 
                 let v = ctx0.op_view_2d(
-                    builder.memory_v,
-                    (input_len, n_embd),
-                    ctx_size * builder.memory_v.element_size(),
-                    (il * ctx_size) * builder.memory_v.element_size() * n_embd
-                        + session_len * builder.memory_v.element_size(),
+                    memory_v,
+                    (sequence_len, n_embd),
+                    ctx_size * memory_v.element_size(),
+                    (il * ctx_size) * memory_v.element_size() * n_embd
+                        + session_len * memory_v.element_size(),
                 );
 
                 // important: storing RoPE-ed version of K in the KV cache!
@@ -221,13 +364,13 @@ impl KnownModel for Llama {
                     .op_permute(
                         &ctx0.op_reshape_3d(
                             &ctx0.op_view_1d(
-                                builder.memory_k,
-                                (session_len + input_len) * n_embd,
-                                il * ctx_size * builder.memory_k.element_size() * n_embd,
+                                memory_k,
+                                (session_len + sequence_len) * n_embd,
+                                il * ctx_size * memory_k.element_size() * n_embd,
                             ),
                             n_embd / n_head,
                             n_head,
-                            session_len + input_len,
+                            session_len + sequence_len,
                         ),
                         (0, 2, 1, 3),
                     )
@@ -255,13 +398,13 @@ impl KnownModel for Llama {
                 // split cached V into n_head heads
                 let v = ctx0
                     .op_view_3d(
-                        builder.memory_v,
-                        (session_len + input_len, n_embd / n_head, n_head),
+                        memory_v,
+                        (session_len + sequence_len, n_embd / n_head, n_head),
                         (
-                            ctx_size * builder.memory_v.element_size(),
-                            ctx_size * builder.memory_v.element_size() * n_embd / n_head,
+                            ctx_size * memory_v.element_size(),
+                            ctx_size * memory_v.element_size() * n_embd / n_head,
                         ),
-                        il * ctx_size * builder.memory_v.element_size() * n_embd,
+                        il * ctx_size * memory_v.element_size() * n_embd,
                     )
                     .set_name("V");
 
@@ -271,104 +414,87 @@ impl KnownModel for Llama {
                 let k_q_v_merged = ctx0.op_permute(&k_q_v, (0, 2, 1, 3)).set_name("KQV_merged");
 
                 // cur = KQV_merged.contiguous().view(n_embd, N)
-                current = ctx0
-                    .op_cpy(
-                        &k_q_v_merged,
-                        &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, input_len),
-                    )
-                    .set_name("KQV_merged_contiguous");
-
-                // projection (no bias)
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
-
-                ctx0.use_scratch(builder.get_scratch(1));
-
-                let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
-
-                // feed-forward network
-                // norm
-                current = ctx0.op_rms_norm(&input_feed_forward);
-
-                // cur = cur*ffn_norm(broadcasted)
-                current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
-
-                let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
-
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
-
-                // SILU activation
-                current = ctx0.op_silu(&current);
-
-                current = ctx0.op_mul(&current, &tmp);
-
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                match &k_q_v_batch {
+                    None => {
+                        k_q_v_single = Some(
+                            ctx0.op_cpy(
+                                &k_q_v_merged,
+                                &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, sequence_len),
+                            )
+                            .set_name("KQV_merged_contiguous"),
+                        );
+                    }
+                    Some(k_q_v_batch) => {
+                        let nb = k_q_v_batch.get_nb();
+                        gf.build_forward_expand(&ctx0.op_cpy(
+                            &k_q_v_merged,
+                            &ctx0.op_view_2d(
+                                k_q_v_batch,
+                                (n_embd, sequence_len),
+                                nb[1],
+                                input_offset * nb[1],
+                            ),
+                        ));
+                    }
+                }
+            }
+            current = k_q_v_single
+                .or(k_q_v_batch)
+                .expect("there is at least one sequence");
 
-                current = ctx0.op_add(&current, &input_feed_forward);
+            // projection (no bias)
+            current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
 
-                // input for next layer
-                input_layer = current;
-            }
+            ctx0.use_scratch(builder.get_scratch(1));
 
-            ctx0.use_scratch(builder.get_scratch(0));
+            let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
 
+            // feed-forward network
             // norm
-            input_layer = ctx0.op_rms_norm(&input_layer);
+            current = ctx0.op_rms_norm(&input_feed_forward);
 
-            // inpL = inpL*norm(broadcasted)
-            input_layer = ctx0.op_mul(&input_layer, &self.norm);
+            // cur = cur*ffn_norm(broadcasted)
+            current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
 
-            let embedding_result: ggml::Tensor = input_layer.share();
+            let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
 
-            ctx0.set_offloading(false);
-            // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
 
-            ctx0.use_scratch(None);
-            (
-                gf,
-                GraphOutputs {
-                    result: input_layer,
-                    embedding_result,
-                },
-            )
-        });
+            // SILU activation
+            current = ctx0.op_silu(&current);
 
-        // finish evaluation
-        common::read_last_token(session, &outputs.result, n_vocab, input_len);
-        common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
-        common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
-    }
+            current = ctx0.op_mul(&current, &tmp);
 
-    fn hyperparameters(&self) -> &Self::Hyperparameters {
-        &self.hyperparameters
-    }
+            current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
 
-    fn tokenizer(&self) -> &Tokenizer {
-        &self.tokenizer
-    }
+            current = ctx0.op_add(&current, &input_feed_forward);
 
-    fn context_size(&self) -> usize {
-        self.params.context_size
-    }
+            // input for next layer
+            input_layer = current;
+        }
 
-    fn bot_token_id(&self) -> Option<TokenId> {
-        None
-    }
+        ctx0.use_scratch(builder.get_scratch(0));
 
-    fn eot_token_id(&self) -> TokenId {
-        self.tokenizer.id("</s>".as_bytes()).unwrap_or(2)
-    }
+        // norm
+        input_layer = ctx0.op_rms_norm(&input_layer);
 
-    fn quantize_tensors() -> Vec<Regex> {
-        vec![Regex::new(".*weight").unwrap()]
-    }
+        // inpL = inpL*norm(broadcasted)
+        input_layer = ctx0.op_mul(&input_layer, &self.norm);
 
-    fn skip_quantize_tensors() -> Vec<Regex> {
-        vec![]
-    }
+        let embedding_result: ggml::Tensor = input_layer.share();
 
-    fn supports_rewind(&self) -> bool {
-        true
+        ctx0.set_offloading(false);
+        // lm_head
+        input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+
+        ctx0.use_scratch(None);
+        (
+            gf,
+            GraphOutputs {
+                result: input_layer,
+                embedding_result,
+            },
+        )
     }
 }
 